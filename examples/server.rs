@@ -0,0 +1,118 @@
+use std::env;
+use std::sync::Arc;
+
+use rmcp::model::{
+    CallToolRequestParam, CallToolResult, Content, Implementation, ListToolsResult,
+    PaginatedRequestParam, ServerCapabilities, ServerInfo, Tool,
+};
+use rmcp::service::RequestContext;
+use rmcp::transport::streamable_http_server::{
+    StreamableHttpServerConfig, StreamableHttpService, session::local::LocalSessionManager,
+};
+use rmcp::{ErrorData, RoleServer, ServerHandler};
+use tmcp_rs::{SenderDid, TmcpServer};
+use tmcp_rs::errors::TmcpError;
+use tmcp_rs::settings::TmcpSettings;
+
+/// Demo MCP server exposing a single `add` tool
+#[derive(Clone)]
+struct Calculator;
+
+impl ServerHandler for Calculator {
+    fn get_info(&self) -> ServerInfo {
+        ServerInfo {
+            capabilities: ServerCapabilities::builder().enable_tools().build(),
+            server_info: Implementation {
+                name: "Demo".to_string(),
+                version: "1.0.0".to_string(),
+                title: Some("TMCP Demo Server".to_string()),
+                website_url: None,
+                icons: None,
+            },
+            ..Default::default()
+        }
+    }
+
+    async fn list_tools(
+        &self,
+        _request: Option<PaginatedRequestParam>,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ListToolsResult, ErrorData> {
+        let serde_json::Value::Object(input_schema) = serde_json::json!({
+            "type": "object",
+            "properties": {
+                "a": { "type": "number" },
+                "b": { "type": "number" }
+            },
+            "required": ["a", "b"]
+        }) else {
+            unreachable!()
+        };
+        Ok(ListToolsResult::with_all_items(vec![Tool::new(
+            "add",
+            "Add two numbers",
+            Arc::new(input_schema),
+        )]))
+    }
+
+    async fn call_tool(
+        &self,
+        request: CallToolRequestParam,
+        context: RequestContext<RoleServer>,
+    ) -> Result<CallToolResult, ErrorData> {
+        if let Some(sender) = SenderDid::from_context(&context) {
+            log::info!("{} called tool {}", sender.as_str(), request.name);
+        }
+        if request.name != "add" {
+            return Err(ErrorData::invalid_params(format!("unknown tool {}", request.name), None));
+        }
+        let arguments = request.arguments.unwrap_or_default();
+        let number = |name: &str| {
+            arguments
+                .get(name)
+                .and_then(|v| v.as_f64())
+                .ok_or_else(|| ErrorData::invalid_params(format!("missing number {name}"), None))
+        };
+        let sum = number("a")? + number("b")?;
+        Ok(CallToolResult::success(vec![Content::text(sum.to_string())]))
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), TmcpError> {
+    env_logger::init();
+
+    let args: Vec<String> = env::args().collect();
+    let bind_address = args.get(1).map(String::as_str).unwrap_or("127.0.0.1:8001");
+
+    let service = StreamableHttpService::new(
+        || Ok(Calculator),
+        LocalSessionManager::default().into(),
+        StreamableHttpServerConfig::default(),
+    );
+    let tmcp_server = TmcpServer::new(
+        "tmcp-server",
+        TmcpSettings {
+            wallet_url: "sqlite://./wallets/server-wallet.sqlite".to_string(),
+            create_identity: true,
+            ..Default::default()
+        },
+        service,
+    )
+    .await?;
+    println!("Server did: {}", tmcp_server.identity().did());
+
+    let router = axum::Router::new().nest_service("/mcp", tmcp_server.clone());
+    let listener = tokio::net::TcpListener::bind(bind_address)
+        .await
+        .map_err(|e| TmcpError::TmcpError(e.to_string()))?;
+    println!("Listening on http://{}/mcp", bind_address);
+    axum::serve(listener, router)
+        .with_graceful_shutdown(async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await
+        .map_err(|e| TmcpError::TmcpError(e.to_string()))?;
+    // Relationships and verified callers not saved yet would be lost with the runtime
+    tmcp_server.flush().await
+}
@@ -0,0 +1,17 @@
+use std::env;
+
+use tmcp_rs::did_server::LocalDidServer;
+use tmcp_rs::errors::TmcpError;
+
+#[tokio::main]
+async fn main() -> Result<(), TmcpError> {
+    env_logger::init();
+
+    let args: Vec<String> = env::args().collect();
+    let address = args.get(1).map(String::as_str).unwrap_or("127.0.0.1:8080");
+
+    let server = LocalDidServer::start(address).await?;
+    println!("DID server listening on http://{}", server.address());
+    server.wait().await;
+    Ok(())
+}
@@ -0,0 +1,51 @@
+use std::env;
+
+use tmcp_rs::errors::TmcpError;
+use tmcp_rs::pins;
+use tmcp_rs::storage;
+use tmcp_rs::settings::TmcpSettings;
+use tmcp_rs::webvh;
+
+const USAGE: &str = "usage: tmcp [--settings <settings.json>] (rotate <alias> | retire <alias> | repin <did> | backup <path>)";
+
+#[tokio::main]
+async fn main() -> Result<(), TmcpError> {
+    env_logger::init();
+
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let settings = if args.first().map(String::as_str) == Some("--settings") {
+        let Some(path) = args.get(1) else {
+            eprintln!("{USAGE}");
+            std::process::exit(2);
+        };
+        let settings: TmcpSettings = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        args.drain(..2);
+        settings
+    } else {
+        TmcpSettings::default()
+    };
+
+    match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        ["rotate", alias] => {
+            let did = webvh::rotate_keys(alias, &settings).await?;
+            println!("rotated keys of {did}");
+        }
+        ["retire", alias] => {
+            let did = webvh::retire(alias, &settings).await?;
+            println!("retired {did}");
+        }
+        ["repin", did] => {
+            let pin = pins::repin(did, &settings).await?;
+            println!("pinned {did}: {}", serde_json::to_string_pretty(&pin)?);
+        }
+        ["backup", path] => {
+            storage::export_backup(&settings, *path).await?;
+            println!("wrote wallet backup to {path}");
+        }
+        _ => {
+            eprintln!("{USAGE}");
+            std::process::exit(2);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,210 @@
+use std::sync::{Arc, RwLock};
+
+use tsp_sdk::AsyncSecureStore;
+
+use crate::{
+    TmcpClient,
+    errors::TmcpError,
+    metadata::MetadataStore,
+    peers,
+    pins::{Pin, PinMismatchCallback, PinStore},
+    resolver::{DidResolver, DidServerResolver},
+    settings::TmcpSettings,
+    storage::{self, Persister, WalletStorage},
+    wallet,
+};
+
+/// Builder for a [`TmcpClient`] with caller supplied dependencies.
+///
+/// Anything not supplied is derived from the settings, like [`TmcpClient::new`] does.
+pub struct TmcpClientBuilder {
+    alias: String,
+    other_did: String,
+    settings: TmcpSettings,
+    http_client: Option<reqwest::Client>,
+    did_server_client: Option<reqwest::Client>,
+    resolver: Option<Arc<dyn DidResolver>>,
+    wallet: Option<AsyncSecureStore>,
+    storage: Option<Arc<dyn WalletStorage>>,
+    pin_store: Option<PinStore>,
+    metadata_store: Option<MetadataStore>,
+    on_pin_mismatch: Option<PinMismatchCallback>,
+}
+
+impl TmcpClientBuilder {
+    /// Starts a client acting as `alias` and talking to `other_did`
+    pub fn new(alias: &str, other_did: &str) -> Self {
+        Self {
+            alias: alias.to_string(),
+            other_did: other_did.to_string(),
+            settings: TmcpSettings::default(),
+            http_client: None,
+            did_server_client: None,
+            resolver: None,
+            wallet: None,
+            storage: None,
+            pin_store: None,
+            metadata_store: None,
+            on_pin_mismatch: None,
+        }
+    }
+
+    /// Sets the settings, defaults to [`TmcpSettings::default`]
+    pub fn settings(mut self, settings: TmcpSettings) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    /// Sets the DID of the peer messages are sealed for
+    pub fn other_did(mut self, other_did: &str) -> Self {
+        self.other_did = other_did.to_string();
+        self
+    }
+
+    /// Uses `http_client` for the MCP server, e.g. to configure proxies, timeouts, user agents or
+    /// share a connection pool. Defaults to a plain client.
+    pub fn http_client(mut self, http_client: reqwest::Client) -> Self {
+        self.http_client = Some(http_client);
+        self
+    }
+
+    /// Uses `did_server_client` to publish and look up DIDs on the DID server.
+    ///
+    /// Defaults to [`TmcpSettings::did_server_client`]; the DID server TLS settings are not applied
+    /// to a supplied client.
+    pub fn did_server_client(mut self, did_server_client: reqwest::Client) -> Self {
+        self.did_server_client = Some(did_server_client);
+        self
+    }
+
+    /// Resolves DIDs with `resolver`, defaults to a [`DidServerResolver`] using the DID server client
+    pub fn resolver(mut self, resolver: Arc<dyn DidResolver>) -> Self {
+        self.resolver = Some(resolver);
+        self
+    }
+
+    /// Uses an already loaded wallet instead of reading it from the storage.
+    ///
+    /// Without a [`storage`](Self::storage) as well, changes to this wallet are not persisted, and
+    /// neither is the TMCP metadata unless a [`metadata_store`](Self::metadata_store) is given.
+    pub fn wallet(mut self, wallet: AsyncSecureStore) -> Self {
+        self.wallet = Some(wallet);
+        self
+    }
+
+    /// Uses an already opened storage instead of the one selected in the settings
+    pub fn storage(mut self, storage: Arc<dyn WalletStorage>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    /// Pins peer keys in `pin_store` instead of the one selected in the settings
+    pub fn pin_store(mut self, pin_store: PinStore) -> Self {
+        self.pin_store = Some(pin_store);
+        self
+    }
+
+    /// Keeps TMCP metadata (peer history, retired DIDs, pending rotations) in `metadata_store`
+    /// instead of the one next to the wallet selected in the settings
+    pub fn metadata_store(mut self, metadata_store: MetadataStore) -> Self {
+        self.metadata_store = Some(metadata_store);
+        self
+    }
+
+    /// Asks `on_mismatch` whether to accept (and re-pin) keys of `other_did` that differ from the
+    /// pinned ones; it gets the DID, the pinned and the resolved keys. Without it they are refused.
+    pub fn on_pin_mismatch(
+        mut self,
+        on_mismatch: impl Fn(&str, &Pin, &Pin) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.on_pin_mismatch = Some(Arc::new(on_mismatch));
+        self
+    }
+
+    /// Provisions the identity and builds the client.
+    ///
+    /// Fails with [`TmcpError::UntrustedPeer`] if the trust policy does not allow `other_did`.
+    /// If the wallet does not hold a DID for the alias yet, one is created and published on the DID
+    /// server. The peer DID (or the new identifier it announced earlier) is resolved and verified,
+    /// including its whole did:webvh history and its pinned keys, and the wallet is saved. Later
+    /// changes are saved in the background.
+    pub async fn build(self) -> Result<TmcpClient, TmcpError> {
+        self.settings.validate()?;
+        self.settings.trust_policy.check(&self.other_did)?;
+        // The DID server client may carry our client certificate, so it never talks to MCP servers
+        let client = match self.did_server_client {
+            Some(client) => client,
+            None => self.settings.did_server_client()?,
+        };
+        let http_client = self.http_client.unwrap_or_default();
+        let resolver: Arc<dyn DidResolver> = match self.resolver {
+            Some(resolver) => resolver,
+            None => Arc::new(DidServerResolver::new(&self.settings, client.clone())),
+        };
+        let metadata = match self.metadata_store {
+            Some(metadata) => metadata,
+            None if self.wallet.is_some() && self.storage.is_none() => MetadataStore::in_memory(),
+            None => MetadataStore::from_settings(&self.settings)?,
+        };
+        let (storage, mut wallet) = match (self.storage, self.wallet) {
+            (storage, Some(wallet)) => (storage, wallet),
+            (Some(storage), None) => {
+                let wallet = wallet::read_wallet(storage.as_ref()).await?;
+                (Some(storage), wallet)
+            }
+            (None, None) => {
+                let storage = storage::open(&self.settings).await?;
+                let wallet = wallet::read_wallet(storage.as_ref()).await?;
+                (Some(storage), wallet)
+            }
+        };
+
+        let (my_did, _created) = wallet::provision(
+            &self.alias,
+            &mut wallet,
+            &metadata,
+            &self.settings,
+            &client,
+            resolver.as_ref(),
+        )
+        .await?;
+        // A new identifier the peer announced earlier replaces the configured one
+        let other_did = peers::current_identifier(&metadata, &self.other_did);
+        let pin_store = match self.pin_store {
+            Some(pin_store) => pin_store,
+            None => PinStore::from_settings(&self.settings)?,
+        };
+        peers::admit(
+            &other_did,
+            &wallet,
+            &metadata,
+            &self.settings,
+            &client,
+            resolver.as_ref(),
+            &pin_store,
+            self.on_pin_mismatch.as_ref(),
+        )
+        .await?;
+        let persister = storage.map(|storage| {
+            Persister::new(storage, wallet.clone(), self.settings.wallet_save_delay())
+        });
+        if let Some(persister) = &persister {
+            persister.flush().await?;
+        }
+        Ok(TmcpClient {
+            inner: http_client,
+            did_server: client,
+            my_did,
+            other_did: Arc::new(RwLock::new(other_did)),
+            wallet,
+            persister,
+            settings: self.settings,
+            resolver,
+            pin_store: Arc::new(pin_store),
+            metadata: Arc::new(metadata),
+            on_pin_mismatch: self.on_pin_mismatch,
+            handshake: Arc::default(),
+            endpoint: Arc::default(),
+        })
+    }
+}
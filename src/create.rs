@@ -1,4 +1,5 @@
-use crate::settings::DidType;
+use crate::settings::{DidType, TmcpSettings};
+use crate::webvh;
 use log::{debug, error, info};
 use reqwest::Url;
 use tsp_sdk::{AsyncSecureStore, Error, OwnedVid, VerifiedVid, Vid, vid::VidError};
@@ -8,38 +9,43 @@ use uuid::Uuid;
 ///
 /// The `vid_wallet` parameter is the wallet in which to store the created DID.
 ///
-/// The `client` parameter is the HTTP client used to publish the DID to the server.
+/// The `settings` parameter provides the type of DID to create, the username to publish it under
+/// (a random one unless `did_username` is set), and the templates of the DID and of the server URLs
+/// used to publish the DID and DID history.
 ///
-/// The `did_server` parameter is the server URL used to publish the DID and DID history.
+/// The `client` parameter is the HTTP client used to publish the DID to the server.
 ///
 /// Returns the created DID if successful, otherwise an error.
 pub async fn create(
     address: Option<&str>,
-    server: &str,
     alias: Option<&str>,
     vid_wallet: &mut AsyncSecureStore,
-    r#type: &DidType,
+    settings: &TmcpSettings,
     client: &reqwest::Client,
-    did_server: &str,
 ) -> Result<OwnedVid, Error> {
-    let username: String = format!(
-        "{}-{}",
-        alias.as_ref().unwrap_or(&"").replace(' ', ""),
-        Uuid::new_v4()
-    )
-    .chars()
-    .take(63)
-    .collect();
-    let transport = if let Some(address) = address {
-        Url::parse(&format!("tcp://{address}")).unwrap()
-    } else {
-        Url::parse(&format!("https://{server}/endpoint/[vid_placeholder]",)).unwrap()
+    let username: String = match &settings.did_username {
+        Some(username) => username.clone(),
+        None => format!(
+            "{}-{}",
+            alias.as_ref().unwrap_or(&"").replace(' ', ""),
+            Uuid::new_v4()
+        )
+        .chars()
+        .take(63)
+        .collect(),
     };
+    let transport = match address {
+        Some(address) => settings.tsp_endpoint(address),
+        None => settings.transport_url(),
+    };
+    let transport = Url::parse(&transport).map_err(|e| {
+        Error::Vid(VidError::InvalidVid(format!("invalid transport endpoint {transport}: {e}")))
+    })?;
 
-    let private_vid = match r#type {
+    let private_vid = match settings.did_type {
         DidType::Web => {
             create_did_web(
-                did_server,
+                settings,
                 transport,
                 vid_wallet,
                 &username,
@@ -59,7 +65,7 @@ pub async fn create(
         DidType::Webvh => {
             let (private_vid, history, update_kid, update_key) =
                 tsp_sdk::vid::did::webvh::create_webvh(
-                    &format!("{did_server}/endpoint/{username}"),
+                    &settings.did_webvh_path(&username),
                     transport,
                 )
                 .await?;
@@ -68,7 +74,7 @@ pub async fn create(
                 .expect("Cannot store update key");
 
             let _: Vid = match client
-                .post(format!("https://{did_server}/add-vid"))
+                .post(settings.publish_url())
                 .json(&private_vid.vid())
                 .send()
                 .await
@@ -91,10 +97,7 @@ pub async fn create(
             );
 
             match client
-                .post(format!(
-                    "https://{did_server}/add-history/{}",
-                    private_vid.vid().identifier()
-                ))
+                .post(settings.publish_history_url(private_vid.vid().identifier()))
                 .json(&history)
                 .send()
                 .await
@@ -114,6 +117,14 @@ pub async fn create(
                 }
             };
             info!("published DID history");
+
+            let log = match history {
+                serde_json::Value::Array(entries) => entries,
+                entry => vec![entry],
+            };
+            webvh::start_pre_rotation(private_vid.identifier(), log, vid_wallet, settings, client)
+                .await
+                .map_err(|e| Error::Vid(VidError::InvalidVid(e.to_string())))?;
             if let Some(alias) = alias {
                 vid_wallet.set_alias(alias.to_string(), private_vid.identifier().to_string())?;
             }
@@ -126,35 +137,32 @@ pub async fn create(
 
 /// Creates a DID document on the server and binds it to the given transport.
 async fn create_did_web(
-    did_server: &str,
+    settings: &TmcpSettings,
     transport: Url,
     vid_wallet: &AsyncSecureStore,
     username: &str,
     alias: Option<&str>,
     client: &reqwest::Client,
 ) -> Result<OwnedVid, Error> {
-    let did = format!(
-        "did:web:{}:endpoint:{username}",
-        did_server.replace(":", "%3A").replace("/", ":")
-    );
+    let did = settings.did_web(username);
 
     if let Some(alias) = alias {
         vid_wallet.set_alias(alias.to_string(), did.clone())?;
         info!("added alias {alias} -> {did}");
     }
 
-    let transport = Url::parse(
-        &transport
-            .as_str()
-            .replace("[vid_placeholder]", &did.replace("%", "%25")),
-    )
-    .unwrap();
+    let transport = transport
+        .as_str()
+        .replace("[vid_placeholder]", &did.replace("%", "%25"));
+    let transport = Url::parse(&transport).map_err(|e| {
+        Error::Vid(VidError::InvalidVid(format!("invalid transport endpoint {transport}: {e}")))
+    })?;
 
     let private_vid = OwnedVid::bind(&did, transport);
     info!("created identity {}", private_vid.identifier());
 
     let response = client
-        .post(format!("https://{did_server}/add-vid"))
+        .post(settings.publish_url())
         .json(&private_vid.vid())
         .send()
         .await
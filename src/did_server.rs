@@ -0,0 +1,221 @@
+//! A local stand-in for the DID server, for hermetic end-to-end tests.
+//!
+//! Implements the endpoints `create` and `get` talk to on a DID server:
+//!
+//! * `POST /add-vid` stores a VID (did:web and did:webvh)
+//! * `POST /add-history/{did}` appends did:webvh log entries
+//! * `GET /endpoint/{name}/did.json` serves the did:web document
+//! * `GET /endpoint/{name}/did.jsonl` serves the did:webvh log
+//!
+//! Everything is kept in memory. [`LocalDidServer::resolver`] resolves the stored VIDs and logs
+//! without going through HTTP, so that it can be handed to [`TmcpClient::new_with_resolver`](crate::TmcpClient::new_with_resolver).
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+};
+
+use axum::{
+    Json, Router,
+    extract::{Path, State},
+    http::{StatusCode, header::CONTENT_TYPE},
+    response::IntoResponse,
+    routing::{get, post},
+};
+use futures::future::BoxFuture;
+use serde_json::Value;
+use tokio::{net::ToSocketAddrs, task::JoinHandle};
+use tsp_sdk::{VerifiedVid, Vid};
+
+use crate::{errors::TmcpError, resolver::DidResolver, settings::TmcpSettings};
+
+#[derive(Debug, Default)]
+struct Registry {
+    /// Published VIDs by DID
+    vids: HashMap<String, Vid>,
+    /// did:webvh log entries by DID
+    histories: HashMap<String, Vec<Value>>,
+}
+
+impl Registry {
+    /// Finds the DID published under `/endpoint/{name}`
+    fn did_for_name(&self, name: &str) -> Option<&str> {
+        let suffix = format!(":endpoint:{name}");
+        self.vids
+            .keys()
+            .chain(self.histories.keys())
+            .find(|did| did.ends_with(&suffix))
+            .map(String::as_str)
+    }
+}
+
+type SharedRegistry = Arc<RwLock<Registry>>;
+
+/// A DID server running in the background on a local address.
+///
+/// The server stops when this value is dropped.
+pub struct LocalDidServer {
+    address: SocketAddr,
+    registry: SharedRegistry,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl LocalDidServer {
+    /// Binds to `address` (use port 0 for a random port) and starts serving.
+    pub async fn start(address: impl ToSocketAddrs) -> Result<Self, TmcpError> {
+        let listener = tokio::net::TcpListener::bind(address).await?;
+        let address = listener.local_addr()?;
+        let registry = SharedRegistry::default();
+        let router = router(registry.clone());
+        let handle = tokio::spawn(async move {
+            if let Err(e) = axum::serve(listener, router).await {
+                log::error!("DID server stopped: {}", e);
+            }
+        });
+        log::info!("DID server listening on {}", address);
+        Ok(Self {
+            address,
+            registry,
+            handle: Some(handle),
+        })
+    }
+
+    /// The socket address the server listens on
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// The `host:port` to use as `did_server` in the settings
+    pub fn did_server(&self) -> String {
+        self.address.to_string()
+    }
+
+    /// A resolver for the VIDs published on this server
+    pub fn resolver(&self) -> LocalDidResolver {
+        LocalDidResolver {
+            registry: self.registry.clone(),
+        }
+    }
+
+    /// Waits until the server stops
+    pub async fn wait(mut self) {
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.await;
+        }
+    }
+}
+
+impl Drop for LocalDidServer {
+    fn drop(&mut self) {
+        if let Some(handle) = &self.handle {
+            handle.abort();
+        }
+    }
+}
+
+/// Resolves DIDs published on a [`LocalDidServer`]
+#[derive(Clone)]
+pub struct LocalDidResolver {
+    registry: SharedRegistry,
+}
+
+impl DidResolver for LocalDidResolver {
+    fn resolve<'a>(&'a self, did: &'a str) -> BoxFuture<'a, Result<(Vid, Option<Value>), TmcpError>> {
+        let resolved = self
+            .registry
+            .read()
+            .expect("registry lock poisoned")
+            .vids
+            .get(did)
+            .cloned()
+            .map(|vid| (vid, None))
+            .ok_or_else(|| TmcpError::UnresolvedDid(did.to_string()));
+        Box::pin(async move { resolved })
+    }
+
+    fn resolve_log<'a>(
+        &'a self,
+        did: &'a str,
+        _client: &'a reqwest::Client,
+        _settings: &'a TmcpSettings,
+    ) -> BoxFuture<'a, Result<Vec<Value>, TmcpError>> {
+        let log = self
+            .registry
+            .read()
+            .expect("registry lock poisoned")
+            .histories
+            .get(did)
+            .cloned()
+            .ok_or_else(|| TmcpError::UnresolvedDid(did.to_string()));
+        Box::pin(async move { log })
+    }
+}
+
+fn router(registry: SharedRegistry) -> Router {
+    Router::new()
+        .route("/add-vid", post(add_vid))
+        .route("/add-history/{did}", post(add_history))
+        .route("/endpoint/{name}/did.json", get(did_document))
+        .route("/endpoint/{name}/did.jsonl", get(did_log))
+        .with_state(registry)
+}
+
+async fn add_vid(State(registry): State<SharedRegistry>, Json(vid): Json<Vid>) -> Json<Vid> {
+    log::info!("published {}", vid.identifier());
+    registry
+        .write()
+        .expect("registry lock poisoned")
+        .vids
+        .insert(vid.identifier().to_string(), vid.clone());
+    Json(vid)
+}
+
+async fn add_history(
+    State(registry): State<SharedRegistry>,
+    Path(did): Path<String>,
+    Json(history): Json<Value>,
+) -> StatusCode {
+    let mut registry = registry.write().expect("registry lock poisoned");
+    let log = registry.histories.entry(did).or_default();
+    match history {
+        Value::Array(entries) => log.extend(entries),
+        entry => log.push(entry),
+    }
+    StatusCode::OK
+}
+
+async fn did_document(
+    State(registry): State<SharedRegistry>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    let registry = registry.read().expect("registry lock poisoned");
+    let vid = registry
+        .did_for_name(&name)
+        .and_then(|did| registry.vids.get(did));
+    match vid {
+        Some(vid) => Json(tsp_sdk::vid::did::web::vid_to_did_document(vid)).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn did_log(
+    State(registry): State<SharedRegistry>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    let registry = registry.read().expect("registry lock poisoned");
+    let log = registry
+        .did_for_name(&name)
+        .and_then(|did| registry.histories.get(did));
+    match log {
+        Some(entries) => {
+            let lines = entries
+                .iter()
+                .map(Value::to_string)
+                .collect::<Vec<_>>()
+                .join("\n");
+            ([(CONTENT_TYPE, "application/jsonl")], lines).into_response()
+        }
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
@@ -22,8 +22,44 @@ pub enum TmcpError {
     TspCesrError(#[from] tsp_sdk::cesr::error::DecodeError),
     #[error("Base64 error: {0}")]
     Base64(#[from] base64::DecodeError),
+    #[error("Invalid {setting} setting: {reason}")]
+    InvalidSetting { setting: String, reason: String },
+    #[error("Unable to resolve DID: {0}")]
+    UnresolvedDid(String),
+    #[error("Wallet storage error: {0}")]
+    WalletStorage(String),
+    #[error("did:webvh error: {0}")]
+    WebVh(String),
+    #[error("Identity {0} is retired")]
+    RetiredIdentity(String),
+    #[error("Invalid did:webvh log of {did}: {reason}")]
+    PeerLogInvalid { did: String, reason: String },
+    #[error("Log of {did} was rewritten, version {version_id} is gone")]
+    PeerLogRewritten { did: String, version_id: String },
+    #[error("Keys of {0} changed without a new log entry")]
+    PeerKeysChanged(String),
+    #[error("Keys of {0} differ from the pinned ones")]
+    PinMismatch(String),
+    #[error("Peer {0} is not trusted")]
+    UntrustedPeer(String),
+    #[error("No identity for {0}; set create_identity to create one, or did (or did_username) and key_backup_path to recover one")]
+    NoIdentity(String),
+    #[error("Unable to recover {did}: {reason}")]
+    RecoveryFailed { did: String, reason: String },
+    #[error("Relationship with {did} failed: {reason}")]
+    RelationshipFailed { did: String, reason: String },
+    #[error("Unable to seal a message for {peer_did}: {reason}")]
+    SealFailed { peer_did: String, reason: String },
+    #[error("Message from {sender} where {peer_did} was expected")]
+    UnexpectedSender { sender: String, peer_did: String },
+    #[error("Received a {kind} instead of the response to the request")]
+    UnexpectedControlMessage { kind: String },
     #[error("Tmcp error: {0}")]
     TmcpError(String),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("WebSocket error: {0}")]
+    WebSocket(#[from] tokio_tungstenite::tungstenite::Error),
     #[error("UTF-8 error: {0}")]
     StringError(#[from] FromUtf8Error),
     #[error("Client initialization error: {0}")]
@@ -1,32 +1,32 @@
 use reqwest::Client;
 use tsp_sdk::vid::did::web::DidDocument;
-use crate::errors;
+use crate::{errors, settings::TmcpSettings, webvh};
 /// Retrieves a DID document from the server and processes it as needed.
 ///
 /// # Parameters
 ///
 /// * client - The HTTP client used to make the request.
-/// * did_server - The URL of the server from which to retrieve the DID document.
+/// * settings - The settings providing the lookup URL template of the DID server.
 /// * user - The username of the user whose DID document to retrieve.
 ///
 /// # Returns
 ///
-/// A Result containing Ok(true) if the DID document was successfully retrieved and processed, otherwise an error.
+/// A Result containing the DID if the DID document was successfully retrieved and processed, otherwise an error.
 pub async fn get_did_doc(
     client: &Client,
-    did_server: &str,
+    settings: &TmcpSettings,
     user: &str,
 ) -> Result<String, errors::TmcpError> {
     match client
-        .get(format!("https://{did_server}/endpoint/{user}/did.jsonl"))
+        .get(settings.lookup_url(user))
         .send()
         .await?
     {
         resp if resp.status().is_success() => {
-            let did_doc = resp.text().await?;
-            // Process the DID document as needed
-            let did_doc: serde_json::Value = serde_json::from_str(&did_doc)?;
-            let Some(did_doc) = did_doc.get("state") else {
+            let did_log = resp.text().await?;
+            // The current DID document is the state of the last log entry
+            let did_log = webvh::parse_log(&did_log)?;
+            let Some(did_doc) = did_log.last().and_then(|entry| entry.get("state")) else {
                 return Err(errors::TmcpError::TspError(tsp_sdk::Error::DecodeState("State not found")));
             };
 
@@ -0,0 +1,106 @@
+//! The TSP identity of a TMCP server.
+//!
+//! [`ServerIdentity`] holds the server's DID with the wallet it lives in. [`TmcpLayer`] (and
+//! through it [`TmcpServer`]) and [`TmcpWebSocketServer`] are built from one, so a server offering
+//! several transports can share a single wallet between them.
+//!
+//! [`TmcpLayer`]: crate::TmcpLayer
+//! [`TmcpServer`]: crate::TmcpServer
+//! [`TmcpWebSocketServer`]: crate::TmcpWebSocketServer
+
+use std::sync::Arc;
+
+use tsp_sdk::AsyncSecureStore;
+
+use crate::{
+    errors::TmcpError,
+    resolver::{DidResolver, DidServerResolver},
+    settings::TmcpSettings,
+    storage::Persister,
+    verify, wallet,
+};
+
+/// A server DID with its wallet, the resolver verifying callers, and the wallet's persister.
+///
+/// Clones share the wallet.
+#[derive(Clone)]
+pub struct ServerIdentity {
+    my_did: String,
+    wallet: AsyncSecureStore,
+    resolver: Arc<dyn DidResolver>,
+    persister: Persister,
+}
+
+impl ServerIdentity {
+    /// Loads (or provisions) the DID for `alias` from the wallet configured in `settings`, like
+    /// [`TmcpClient::new`](crate::TmcpClient::new) does.
+    pub async fn load(alias: &str, settings: &TmcpSettings) -> Result<Self, TmcpError> {
+        let resolver = Arc::new(DidServerResolver::from_settings(settings)?);
+        Self::load_with_resolver(alias, settings, resolver).await
+    }
+
+    /// Like [`ServerIdentity::load`], resolving DIDs with `resolver` instead of the network
+    pub async fn load_with_resolver(
+        alias: &str,
+        settings: &TmcpSettings,
+        resolver: Arc<dyn DidResolver>,
+    ) -> Result<Self, TmcpError> {
+        let client = settings.did_server_client()?;
+        let wallet::LoadedWallet {
+            storage,
+            wallet,
+            my_did,
+            created,
+        } = wallet::load_wallet(alias, settings, &client, resolver.as_ref()).await?;
+        let persister = Persister::new(storage, wallet.clone(), settings.wallet_save_delay());
+        if created {
+            persister.flush().await?;
+        }
+        Ok(Self::from_parts(my_did, wallet, resolver, persister))
+    }
+
+    /// An identity for a wallet that already holds `my_did`
+    pub(crate) fn from_parts(
+        my_did: String,
+        wallet: AsyncSecureStore,
+        resolver: Arc<dyn DidResolver>,
+        persister: Persister,
+    ) -> Self {
+        Self {
+            my_did,
+            wallet,
+            resolver,
+            persister,
+        }
+    }
+
+    /// The DID clients have to seal their messages for
+    pub fn did(&self) -> &str {
+        &self.my_did
+    }
+
+    /// Saves pending wallet changes now instead of after `wallet_save_delay_ms`.
+    ///
+    /// Call it before the runtime shuts down, pending background saves do not survive it.
+    pub async fn flush(&self) -> Result<(), TmcpError> {
+        self.persister.flush().await
+    }
+
+    pub(crate) fn wallet(&self) -> &AsyncSecureStore {
+        &self.wallet
+    }
+
+    /// Reports a change to the wallet, to be saved in the background
+    pub(crate) fn changed(&self) {
+        self.persister.changed();
+    }
+
+    /// Makes sure the wallet can seal to (and open messages from) `did`, saving newly verified DIDs
+    pub(crate) async fn ensure_verified(&self, did: &str) -> Result<(), TmcpError> {
+        if !self.wallet.has_verified_vid(did)? {
+            verify::verify_did(did, &self.wallet, None, self.resolver.as_ref()).await?;
+            self.persister.changed();
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,322 @@
+//! Tower middleware that adds TSP to an existing MCP HTTP service.
+//!
+//! [`TmcpLayer`] can wrap any service speaking rmcp's streamable HTTP protocol (for example an axum
+//! router with an rmcp `StreamableHttpService` mounted on it). Request bodies are opened as TSP
+//! messages before they reach the inner service; JSON responses are sealed as a whole and
+//! `text/event-stream` responses are sealed per SSE event, for the caller's DID.
+
+use std::{
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use futures::{StreamExt, future::BoxFuture};
+use http::{
+    Request, Response, StatusCode, Uri,
+    header::{CONTENT_LENGTH, CONTENT_TYPE},
+};
+use http_body::Body;
+use http_body_util::{BodyExt, Full, combinators::UnsyncBoxBody};
+use rmcp::service::RequestContext;
+use rmcp::transport::common::http_header::{EVENT_STREAM_MIME_TYPE, JSON_MIME_TYPE};
+use rmcp::RoleServer;
+use sse_stream::{SseBody, SseStream};
+use tower_layer::Layer;
+use tsp_sdk::AsyncSecureStore;
+
+use crate::{
+    errors::TmcpError,
+    identity::ServerIdentity,
+    resolver::DidResolver,
+    settings::{TmcpSettings, TrustPolicy},
+    tsp_messages::{self, TspMessage},
+};
+
+/// Boxed error type used by the response bodies of [`TmcpService`]
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+/// Response body produced by [`TmcpService`]
+pub type TmcpBody = UnsyncBoxBody<Bytes, BoxError>;
+
+/// DID of the authenticated sender of an MCP request, taken from its TSP envelope.
+///
+/// [`TmcpService`] stores it in the HTTP request extensions. rmcp hands the request parts to
+/// handlers through the request context, so tools can use [`SenderDid::from_context`] to authorise
+/// per caller DID.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SenderDid(pub String);
+
+impl SenderDid {
+    /// Looks up the sender DID of the MCP request handled with `context`
+    pub fn from_context(context: &RequestContext<RoleServer>) -> Option<&SenderDid> {
+        context
+            .extensions
+            .get::<http::request::Parts>()?
+            .extensions
+            .get::<SenderDid>()
+    }
+
+    /// The sender DID as a string
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Layer sealing an MCP HTTP service with the server's TSP identity.
+///
+/// Callers identify themselves with a `did` query parameter, as done by
+/// [`TmcpClient::create_transport`](crate::TmcpClient::create_transport).
+///
+/// Request bodies that cannot be opened never reach the inner service: they are refused with 400
+/// whatever the `open_failure_policy`, as no request id can be read from them.
+#[derive(Clone)]
+pub struct TmcpLayer {
+    identity: ServerIdentity,
+    trust_policy: Arc<TrustPolicy>,
+    require_relationship: bool,
+}
+
+impl TmcpLayer {
+    /// Loads (or provisions) the server identity for `alias`, see [`ServerIdentity::load`]
+    pub async fn new(alias: &str, settings: TmcpSettings) -> Result<Self, TmcpError> {
+        let identity = ServerIdentity::load(alias, &settings).await?;
+        Ok(Self::from_identity(identity, &settings))
+    }
+
+    /// Like [`TmcpLayer::new`], resolving DIDs with `resolver` instead of the network
+    pub async fn new_with_resolver(
+        alias: &str,
+        settings: TmcpSettings,
+        resolver: Arc<dyn DidResolver>,
+    ) -> Result<Self, TmcpError> {
+        let identity = ServerIdentity::load_with_resolver(alias, &settings, resolver).await?;
+        Ok(Self::from_identity(identity, &settings))
+    }
+
+    /// Seals with an already loaded `identity`, applying the trust policy and relationship
+    /// requirement of `settings`
+    pub fn from_identity(identity: ServerIdentity, settings: &TmcpSettings) -> Self {
+        Self {
+            identity,
+            trust_policy: Arc::new(settings.trust_policy.clone()),
+            require_relationship: settings.require_relationship,
+        }
+    }
+
+    /// The identity callers talk to
+    pub fn identity(&self) -> &ServerIdentity {
+        &self.identity
+    }
+
+    /// Saves pending wallet changes now, see [`ServerIdentity::flush`]
+    pub async fn flush(&self) -> Result<(), TmcpError> {
+        self.identity.flush().await
+    }
+}
+
+impl<S> Layer<S> for TmcpLayer {
+    type Service = TmcpService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        TmcpService {
+            inner,
+            identity: self.identity.clone(),
+            trust_policy: self.trust_policy.clone(),
+            require_relationship: self.require_relationship,
+        }
+    }
+}
+
+/// Service produced by [`TmcpLayer`]
+#[derive(Clone)]
+pub struct TmcpService<S> {
+    inner: S,
+    identity: ServerIdentity,
+    trust_policy: Arc<TrustPolicy>,
+    require_relationship: bool,
+}
+
+impl<S> TmcpService<S> {
+    /// The identity callers talk to
+    pub fn identity(&self) -> &ServerIdentity {
+        &self.identity
+    }
+}
+
+impl<S, B, R> tower_service::Service<Request<B>> for TmcpService<S>
+where
+    S: tower_service::Service<Request<B>, Response = Response<R>> + Clone + Send + 'static,
+    S::Future: Send,
+    B: Body + From<Bytes> + Send + 'static,
+    B::Data: Send,
+    B::Error: std::fmt::Display,
+    R: Body<Data = Bytes> + Send + 'static,
+    R::Error: Into<BoxError>,
+{
+    type Response = Response<TmcpBody>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<B>) -> Self::Future {
+        // Take the service that was polled ready and leave a fresh clone behind
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let identity = self.identity.clone();
+        let trust_policy = self.trust_policy.clone();
+        let require_relationship = self.require_relationship;
+        Box::pin(async move {
+            let (mut parts, body) = request.into_parts();
+            let Some(caller_did) = caller_did(&parts.uri) else {
+                return Ok(error_response(StatusCode::BAD_REQUEST, "missing did query parameter"));
+            };
+            if !trust_policy.allows(&caller_did) {
+                log::warn!("refused untrusted caller {}", caller_did);
+                return Ok(error_response(StatusCode::FORBIDDEN, "caller did is not trusted"));
+            }
+            if let Err(e) = identity.ensure_verified(&caller_did).await {
+                log::error!("failed to verify caller {}: {}", caller_did, e);
+                return Ok(error_response(StatusCode::UNAUTHORIZED, "unable to verify caller did"));
+            }
+
+            let wallet = identity.wallet();
+            let my_did = identity.did();
+            let body = match body.collect().await {
+                Ok(body) => body.to_bytes(),
+                Err(e) => {
+                    return Ok(error_response(StatusCode::BAD_REQUEST, e.to_string()));
+                }
+            };
+            let body = if body.is_empty() {
+                body
+            } else {
+                let data = String::from_utf8_lossy(&body).into_owned();
+                match tsp_messages::open_message(data, wallet) {
+                    Ok(message) if message.sender() != Some(caller_did.as_str()) => {
+                        log::error!("message from {:?} does not match caller {}", message.sender(), caller_did);
+                        return Ok(error_response(StatusCode::UNAUTHORIZED, "sender does not match did query parameter"));
+                    }
+                    Ok(TspMessage::Generic(message)) => {
+                        parts.extensions.insert(SenderDid(message.sender));
+                        Bytes::from(message.payload)
+                    }
+                    Ok(TspMessage::RelationshipRequest { thread_id, .. }) => {
+                        let accept = tsp_messages::relationship_accept(wallet, my_did, &caller_did, thread_id);
+                        return Ok(match accept {
+                            Ok(accept) => {
+                                log::info!("accepted relationship with {}", caller_did);
+                                identity.changed();
+                                Response::builder()
+                                    .status(StatusCode::OK)
+                                    .header(CONTENT_TYPE, JSON_MIME_TYPE)
+                                    .body(full_body(accept))
+                                    .expect("valid response")
+                            }
+                            Err(e) => {
+                                log::error!("failed to accept relationship with {}: {}", caller_did, e);
+                                error_response(StatusCode::INTERNAL_SERVER_ERROR, "unable to accept relationship")
+                            }
+                        });
+                    }
+                    Ok(control) => {
+                        // Opening the message already updated the relationship in the wallet
+                        log::info!("received {} from {}", control.kind(), caller_did);
+                        identity.changed();
+                        return Ok(error_response(StatusCode::ACCEPTED, Bytes::new()));
+                    }
+                    Err(e) => {
+                        log::error!("failed to open message: {}", e);
+                        return Ok(error_response(StatusCode::BAD_REQUEST, "unable to open TSP message"));
+                    }
+                }
+            };
+            if require_relationship && !tsp_messages::is_related(wallet, my_did, &caller_did).unwrap_or(false) {
+                log::warn!("refused caller {} without a relationship", caller_did);
+                return Ok(error_response(StatusCode::FORBIDDEN, "no TSP relationship with caller"));
+            }
+            parts.headers.remove(CONTENT_LENGTH);
+
+            let response = inner.call(Request::from_parts(parts, B::from(body))).await?;
+            Ok(seal_response(response, wallet.clone(), my_did.to_string(), caller_did).await)
+        })
+    }
+}
+
+/// Extracts the caller DID from the `did` query parameter, exactly as the client appended it
+pub(crate) fn caller_did(uri: &Uri) -> Option<String> {
+    uri.query()?
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("did="))
+        .filter(|did| !did.is_empty())
+        .map(str::to_string)
+}
+
+/// Seal a JSON or SSE response for `caller_did`; other responses pass through unchanged
+async fn seal_response<R>(
+    response: Response<R>,
+    wallet: AsyncSecureStore,
+    my_did: String,
+    caller_did: String,
+) -> Response<TmcpBody>
+where
+    R: Body<Data = Bytes> + Send + 'static,
+    R::Error: Into<BoxError>,
+{
+    let (mut parts, body) = response.into_parts();
+    let content_type = parts
+        .headers
+        .get(CONTENT_TYPE)
+        .map(|ct| ct.as_bytes().to_vec());
+
+    match content_type {
+        Some(ct) if ct.starts_with(EVENT_STREAM_MIME_TYPE.as_bytes()) => {
+            let body = body.map_err(Into::into);
+            let events = SseStream::new(body).map(move |result| {
+                let mut sse = result.map_err(BoxError::from)?;
+                if let Some(data) = sse.data.take() {
+                    let sealed = tsp_messages::seal_message(data, &wallet, &my_did, &caller_did)?;
+                    sse.data = Some(sealed);
+                }
+                Ok::<_, BoxError>(sse)
+            });
+            Response::from_parts(parts, SseBody::new(events).boxed_unsync())
+        }
+        Some(ct) if ct.starts_with(JSON_MIME_TYPE.as_bytes()) => {
+            let body = match body.collect().await {
+                Ok(body) => body.to_bytes(),
+                Err(e) => {
+                    let e: BoxError = e.into();
+                    return error_response(StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+                }
+            };
+            let data = String::from_utf8_lossy(&body).into_owned();
+            match tsp_messages::seal_message(data, &wallet, &my_did, &caller_did) {
+                Ok(sealed) => {
+                    parts.headers.remove(CONTENT_LENGTH);
+                    Response::from_parts(parts, full_body(sealed))
+                }
+                Err(e) => {
+                    log::error!("failed to seal message: {}", e);
+                    error_response(StatusCode::INTERNAL_SERVER_ERROR, "unable to seal TSP message")
+                }
+            }
+        }
+        _ => Response::from_parts(parts, body.map_err(Into::into).boxed_unsync()),
+    }
+}
+
+fn full_body(data: impl Into<Bytes>) -> TmcpBody {
+    Full::new(data.into())
+        .map_err(|never| match never {})
+        .boxed_unsync()
+}
+
+fn error_response(status: StatusCode, message: impl Into<Bytes>) -> Response<TmcpBody> {
+    Response::builder()
+        .status(status)
+        .body(full_body(message))
+        .expect("valid error response")
+}
@@ -4,14 +4,13 @@
 //! It builds upon the core `rmcp` crate to offer additional transport mechanisms and helpers.
 //!
 
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 
-use crate::create::create;
 use errors::TmcpError;
-use futures::{StreamExt, stream::BoxStream};
+use futures::{StreamExt, future, stream::BoxStream};
 use http::header::CONTENT_TYPE;
 use reqwest::header::ACCEPT;
-use rmcp::model::ServerJsonRpcMessage;
+use rmcp::model::{ErrorData, RequestId, ServerJsonRpcMessage};
 use rmcp::transport::common::http_header::{EVENT_STREAM_MIME_TYPE, HEADER_LAST_EVENT_ID, HEADER_SESSION_ID, JSON_MIME_TYPE};
 use rmcp::transport::streamable_http_client::SseError;
 use rmcp::{
@@ -20,101 +19,361 @@ use rmcp::{
         StreamableHttpClient, StreamableHttpError, StreamableHttpPostResponse,
     },
 };
+use settings::OpenFailurePolicy;
 use sse_stream::{Sse, SseStream};
-use tsp_sdk::{AskarSecureStorage, AsyncSecureStore, SecureStorage, VerifiedVid};
-use uuid::Uuid;
+use tsp_messages::TspMessage;
+use tsp_sdk::AsyncSecureStore;
+mod builder;
 mod create;
+#[cfg(feature = "did-server")]
+pub mod did_server;
 pub mod errors;
 mod get;
+pub mod identity;
+pub mod layer;
+pub mod metadata;
+mod peers;
+pub mod pins;
+pub mod resolver;
+pub mod server;
 mod tsp_messages;
 pub mod settings;
+pub mod stdio;
+pub mod storage;
+pub mod tcp;
 #[cfg(test)]
 mod tests;
+pub mod transport;
 mod verify;
+mod wallet;
+pub mod webvh;
+pub mod websocket;
+
+pub use builder::TmcpClientBuilder;
+pub use identity::ServerIdentity;
+pub use layer::{SenderDid, TmcpLayer};
+pub use resolver::{DidResolver, DidServerResolver, InMemoryResolver, NetworkResolver};
+pub use server::TmcpServer;
+pub use websocket::TmcpWebSocketServer;
 
 #[derive(Clone)]
 pub struct TmcpClient {
+    /// Client for the MCP server
     inner: reqwest::Client,
+    /// Client for the DID server, configured with the DID server TLS settings
+    did_server: reqwest::Client,
     my_did: String,
-    other_did: String,
+    other_did: Arc<RwLock<String>>,
     wallet: AsyncSecureStore,
+    /// Saves wallet changes, absent if the wallet was supplied without a storage
+    persister: Option<storage::Persister>,
+    settings: settings::TmcpSettings,
+    resolver: Arc<dyn DidResolver>,
+    /// Pinned keys of `other_did` and of the identifiers it switches to
+    pin_store: Arc<pins::PinStore>,
+    /// Peer history, the identifiers `other_did` switched to, and the state of our DID
+    metadata: Arc<metadata::MetadataStore>,
+    on_pin_mismatch: Option<pins::PinMismatchCallback>,
+    /// Held while setting up the relationship with `other_did`, so only one handshake runs
+    handshake: Arc<tokio::sync::Mutex<()>>,
+    /// The MCP endpoint and auth token of the last request, where [`TmcpClient::close`] sends the cancel
+    endpoint: Arc<RwLock<Option<(Arc<str>, Option<String>)>>>,
 }
 
 impl TmcpClient {
     pub async fn new(alias: &str, other_did: &str, settings: settings::TmcpSettings) -> Result<Self, TmcpError> {
-        let wallet_alias = if settings.use_webvh {
-            format!("{}vh", alias)
-        } else {
-            alias.to_string()
-        };
-        // TODO: Create AskarSecureStorage if not exists
-        let storage =
-            AskarSecureStorage::open(&settings.wallet_url, settings.wallet_password.as_bytes())
-                .await;
-        let storage = match storage {
-            Err(e) => {
-                log::warn!("Warning:unable to open storage: {:?}. Creating a new one", e);
-                AskarSecureStorage::new(&settings.wallet_url, settings.wallet_password.as_bytes())
-                    .await
+        Self::builder(alias, other_did).settings(settings).build().await
+    }
+
+    /// Like [`TmcpClient::new`], resolving DIDs with `resolver` instead of the network
+    pub async fn new_with_resolver(
+        alias: &str,
+        other_did: &str,
+        settings: settings::TmcpSettings,
+        resolver: Arc<dyn DidResolver>,
+    ) -> Result<Self, TmcpError> {
+        Self::builder(alias, other_did)
+            .settings(settings)
+            .resolver(resolver)
+            .build()
+            .await
+    }
+
+    /// Starts building a client acting as `alias` and talking to `other_did`
+    pub fn builder(alias: &str, other_did: &str) -> TmcpClientBuilder {
+        TmcpClientBuilder::new(alias, other_did)
+    }
+
+    /// Saves pending wallet changes now instead of after `wallet_save_delay_ms`.
+    ///
+    /// Call it before the runtime shuts down, pending background saves do not survive it.
+    pub async fn flush(&self) -> Result<(), TmcpError> {
+        match &self.persister {
+            Some(persister) => persister.flush().await,
+            None => Ok(()),
+        }
+    }
+
+    /// Generates new signing and encryption keys for our did:webvh identity, publishes them as a
+    /// signed log entry on the DID server and saves them in the wallet (and the key backup).
+    pub async fn rotate_keys(&self) -> Result<(), TmcpError> {
+        let rotation = webvh::prepare_rotation(
+            &self.my_did,
+            &self.wallet,
+            &self.metadata,
+            &self.settings,
+            &self.did_server,
+            self.resolver.as_ref(),
+        )
+        .await?;
+        // Keep the new keys safe before the DID server names them
+        self.flush().await?;
+        wallet::write_backup(&self.wallet, &self.settings).await?;
+        webvh::publish_rotation(
+            rotation,
+            &self.wallet,
+            &self.metadata,
+            &self.settings,
+            &self.did_server,
+        )
+        .await?;
+        wallet::write_backup(&self.wallet, &self.settings).await?;
+        self.flush().await
+    }
+
+    /// Retires our identity: deactivates our did:webvh on the DID server and marks it retired next
+    /// to the wallet, so that it is refused from now on.
+    pub async fn retire(self) -> Result<(), TmcpError> {
+        webvh::deactivate(
+            &self.my_did,
+            &self.wallet,
+            &self.metadata,
+            &self.settings,
+            &self.did_server,
+        )
+        .await?;
+        self.flush().await
+    }
+
+    /// Shuts the client down: with `cancel_relationship_on_close`, cancels the TSP relationship
+    /// with `other_did` at the MCP endpoint last used, and saves pending wallet changes.
+    pub async fn close(&self) -> Result<(), TmcpError> {
+        let endpoint = self.endpoint.read().expect("endpoint lock poisoned").clone();
+        let cancelled = match endpoint {
+            Some((uri, auth_token)) if self.settings.cancel_relationship_on_close => {
+                self.cancel_relationship(&uri, auth_token.as_deref()).await
             }
-            _ => storage,
+            _ => Ok(()),
         };
-        let storage = storage?;
-        let (vids, aliases, keys) = storage.read().await?;
-        let mut wallet = AsyncSecureStore::new();
-        wallet.import(vids, aliases, keys)?;
-        
-        let mut my_did: Option<String> = wallet.resolve_alias(&wallet_alias)?;
-        let did_server = settings.did_server.to_string();
-        let client = reqwest::Client::new();
-        if let Some(my_did) = &my_did {
-            //Resolve and verify public key material for a VID identified by vid and add it to the wallet as a relationship
-            verify::verify_did(my_did, &wallet, None).await?;
-        } else {
-            let address = settings.did_server.to_string();
-            let username = format!("{}-{}", alias, Uuid::new_v4());
-            let published_did = match get::get_did_doc(&client, &did_server, &username).await {
-                Ok(published_did) => Some(published_did),
-                Err(e) => {
-                    log::warn!("Warning:unable to get published did: {:?}. Creating a new one", e);
-                    None
+        self.flush().await?;
+        cancelled
+    }
+
+    /// The DID messages are sealed for; it changes when the peer announces a new identifier
+    pub fn other_did(&self) -> String {
+        self.other_did.read().expect("other_did lock poisoned").clone()
+    }
+
+    /// Reports a change to the wallet, e.g. a new relationship, to be saved in the background
+    fn wallet_changed(&self) {
+        if let Some(persister) = &self.persister {
+            persister.changed();
+        }
+    }
+
+    /// Open the TSP message in every SSE event; control messages are acted upon and dropped.
+    ///
+    /// Events that cannot be opened are handled according to `open_failure_policy`. Under
+    /// [`OpenFailurePolicy::JsonRpcError`](settings::OpenFailurePolicy::JsonRpcError) the first one
+    /// answers `request_id` with an error and ends the stream.
+    fn open_sse_stream(
+        &self,
+        event_stream: impl futures::Stream<Item = Result<Sse, SseError>> + Send + 'static,
+        request_id: Option<RequestId>,
+    ) -> BoxStream<'static, Result<Sse, SseError>> {
+        let client = self.clone();
+        event_stream
+            .filter_map(move |result| {
+                let client = client.clone();
+                let request_id = request_id.clone();
+                async move {
+                    let mut sse = match result {
+                        Ok(sse) => sse,
+                        Err(e) => return Some((Err(e), false)),
+                    };
+                    let Some(data) = sse.data.take() else {
+                        return Some((Ok(sse), false));
+                    };
+                    match client.open_from_peer(data) {
+                        Ok(TspMessage::Generic(message)) => {
+                            sse.data = Some(message.payload);
+                            Some((Ok(sse), false))
+                        }
+                        Ok(control) => {
+                            client.handle_control(control).await;
+                            None
+                        }
+                        Err(e) => {
+                            log::error!("failed to open message: {}", e);
+                            match (client.settings.open_failure_policy, request_id) {
+                                (OpenFailurePolicy::FailStream, _) => {
+                                    Some((Err(SseError::Body(Box::new(e))), true))
+                                }
+                                // The error is the answer, so nothing may follow it for this request
+                                (OpenFailurePolicy::JsonRpcError, Some(request_id)) => {
+                                    let error = open_failure_error(&e, request_id);
+                                    sse.data = Some(serde_json::to_string(&error).ok()?);
+                                    Some((Ok(sse), true))
+                                }
+                                // Without a request in flight there is nothing to answer
+                                (OpenFailurePolicy::Drop | OpenFailurePolicy::JsonRpcError, _) => None,
+                            }
+                        }
+                    }
                 }
-            };
-            if let Some(published_did) = published_did {
-                my_did = Some(published_did.clone());
-                verify::verify_did(&published_did, &wallet, None).await?;
-            } else {
-                let private_vid = create(
-                    Some(&settings.did_server),
-                    &address,
-                    Some(alias),
-                    &mut wallet,
-                    &settings.did_type,
-                    &client,
-                    &did_server,
-                )
-                .await?;
-                my_did = Some(private_vid.identifier().to_string());
-                let meta_data = verify::verify_did(private_vid.identifier(), &wallet, None).await?;
-                wallet.add_private_vid(private_vid, meta_data)?;   
+            })
+            // End the stream after the event that failed it
+            .scan(false, |failed, (event, last)| {
+                let event = (!*failed).then_some(event);
+                *failed |= last;
+                future::ready(event)
+            })
+            .boxed()
+    }
+
+    /// Opens a TSP message; generic messages not sent by `other_did` fail like messages that
+    /// cannot be opened, control messages are checked by [`TmcpClient::handle_control`]
+    #[allow(clippy::result_large_err)]
+    fn open_from_peer(&self, data: String) -> Result<TspMessage, TmcpError> {
+        let message = tsp_messages::open_message(data, &self.wallet)?;
+        let other_did = self.other_did();
+        match message {
+            TspMessage::Generic(message) if message.sender != other_did => Err(TmcpError::UnexpectedSender {
+                sender: message.sender,
+                peer_did: other_did,
+            }),
+            message => Ok(message),
+        }
+    }
+
+    /// Acts on a TSP control message; opening it already updated the relationship in the wallet
+    async fn handle_control(&self, message: TspMessage) {
+        let other_did = self.other_did();
+        if message.sender() != Some(other_did.as_str()) {
+            log::error!("dropping {} from unexpected sender {:?}", message.kind(), message.sender());
+            return;
+        }
+        match message {
+            TspMessage::RelationshipRequest { .. }
+            | TspMessage::RelationshipAccept { .. }
+            | TspMessage::RelationshipCancel { .. } => {
+                log::info!("received {} from {}", message.kind(), other_did);
+                self.wallet_changed();
+            }
+            TspMessage::NewIdentifier { new_vid, .. } => {
+                if let Err(e) = self.switch_identifier(&new_vid).await {
+                    log::error!("refused new identifier {} of {}: {}", new_vid, other_did, e);
+                }
+            }
+            TspMessage::Referral { referred_vid, .. } => {
+                log::info!("{} referred us to {}", other_did, referred_vid);
+            }
+            TspMessage::Generic(_) | TspMessage::Unsupported { .. } => {
+                log::debug!("ignoring {} from {}", message.kind(), other_did);
+            }
+        }
+    }
+
+    /// Seals for `new_vid` from now on, after checking it like the original `other_did`.
+    ///
+    /// The switch is saved next to the wallet, so later clients for the original `other_did` use
+    /// `new_vid` too.
+    async fn switch_identifier(&self, new_vid: &str) -> Result<(), TmcpError> {
+        peers::admit(
+            new_vid,
+            &self.wallet,
+            &self.metadata,
+            &self.settings,
+            &self.did_server,
+            self.resolver.as_ref(),
+            &self.pin_store,
+            self.on_pin_mismatch.as_ref(),
+        )
+        .await?;
+        let previous = std::mem::replace(
+            &mut *self.other_did.write().expect("other_did lock poisoned"),
+            new_vid.to_string(),
+        );
+        self.metadata.set_identifier(&previous, new_vid)?;
+        log::info!("{} is now known as {}", previous, new_vid);
+        self.wallet_changed();
+        Ok(())
+    }
+
+    /// Sets up a TSP relationship with `other_did` through the MCP endpoint `uri`, unless the
+    /// wallet already holds one. The accepted relationship is saved with the wallet.
+    async fn ensure_relationship(&self, uri: &str, auth_token: Option<&str>) -> Result<(), TmcpError> {
+        let _handshake = self.handshake.lock().await;
+        let other_did = self.other_did();
+        if tsp_messages::is_related(&self.wallet, &self.my_did, &other_did)? {
+            return Ok(());
+        }
+        let relationship_request = tsp_messages::relationship_request(&self.wallet, &self.my_did, &other_did)?;
+        let mut request = self
+            .inner
+            .post(uri)
+            .header(CONTENT_TYPE, JSON_MIME_TYPE)
+            .body(relationship_request);
+        if let Some(auth_token) = auth_token {
+            request = request.bearer_auth(auth_token);
+        }
+        let response = request.send().await?.error_for_status()?;
+        let failed = |reason: String| TmcpError::RelationshipFailed {
+            did: other_did.clone(),
+            reason,
+        };
+        match tsp_messages::open_message(response.text().await?, &self.wallet)? {
+            TspMessage::RelationshipAccept { sender, .. } if sender == other_did => {}
+            message => {
+                return Err(failed(format!("expected a relationship accept, got {}", message.kind())));
             }
-            verify::verify_did(other_did, &wallet, None).await?;
-            let wallet_export = wallet.export()?;
-            storage.persist(wallet_export).await?;
         }
-        let my_did = my_did.unwrap_or_default();
-        Ok(Self {
-            inner: reqwest::Client::new(),
-            my_did,
-            other_did: other_did.to_string(),
-            wallet,
-        })
+        if !tsp_messages::is_related(&self.wallet, &self.my_did, &other_did)? {
+            return Err(failed("the accept does not match our request".to_string()));
+        }
+        log::info!("established relationship with {}", other_did);
+        self.wallet_changed();
+        Ok(())
+    }
+
+    /// Cancels the TSP relationship with `other_did` through the MCP endpoint `uri`, if there is one.
+    /// The wallet drops the relationship even if the server cannot be reached.
+    async fn cancel_relationship(&self, uri: &str, auth_token: Option<&str>) -> Result<(), TmcpError> {
+        let _handshake = self.handshake.lock().await;
+        let other_did = self.other_did();
+        if !tsp_messages::is_related(&self.wallet, &self.my_did, &other_did)? {
+            return Ok(());
+        }
+        let relationship_cancel = tsp_messages::relationship_cancel(&self.wallet, &self.my_did, &other_did)?;
+        self.wallet_changed();
+        let mut request = self
+            .inner
+            .post(uri)
+            .header(CONTENT_TYPE, JSON_MIME_TYPE)
+            .body(relationship_cancel);
+        if let Some(auth_token) = auth_token {
+            request = request.bearer_auth(auth_token);
+        }
+        request.send().await?.error_for_status()?;
+        log::info!("cancelled relationship with {}", other_did);
+        Ok(())
     }
 
     /// Handle HTTP response and apply TSP transformations
     async fn handle_response(
         &self,
         response: reqwest::Response,
+        request_id: Option<RequestId>,
     ) -> Result<StreamableHttpPostResponse, StreamableHttpError<TmcpError>> {
         use http::header::WWW_AUTHENTICATE;
         use rmcp::transport::common::http_header::{
@@ -159,27 +418,8 @@ impl TmcpClient {
         match content_type {
             Some(ct) if ct.as_bytes().starts_with(EVENT_STREAM_MIME_TYPE.as_bytes()) => {
                 let event_stream = SseStream::from_byte_stream(response.bytes_stream());
-                // Apply TSP open_message transformation to SSE stream if available
-                let wallet_clone = self.wallet.clone();
-                let wrapped_stream = event_stream.map(move |result| {
-                    result.map(|mut sse| {
-                        let Some(data) = sse.data.take() else {
-                            return sse;
-                        };
-                        let processed_data = tsp_messages::open_message(data, &wallet_clone);
-                        match processed_data {
-                            Ok(processed_data) => {
-                                sse.data = Some(processed_data);
-                            }
-                            Err(e) => {
-                                log::error!("failed to open message: {}", e);
-                            }
-                        }
-                        sse
-                    })
-                });
                 Ok(StreamableHttpPostResponse::Sse(
-                    wrapped_stream.boxed(),
+                    self.open_sse_stream(event_stream, request_id),
                     session_id,
                 ))
             }
@@ -190,10 +430,34 @@ impl TmcpClient {
                     .map_err(|e| StreamableHttpError::Client(TmcpError::Reqwest(e)))?;
 
                 // Apply TSP open_message transformation if available
-                let processed_body = tsp_messages::open_message(body, &self.wallet.clone())
-                    .map_err(StreamableHttpError::Client)?;
+                let opened = match self.open_from_peer(body) {
+                    Ok(TspMessage::Generic(message)) => Ok(message),
+                    // Only a notification goes without a response
+                    Ok(control) if request_id.is_none() => {
+                        self.handle_control(control).await;
+                        return Ok(StreamableHttpPostResponse::Accepted);
+                    }
+                    Ok(control) => {
+                        let kind = control.kind().to_string();
+                        self.handle_control(control).await;
+                        Err(TmcpError::UnexpectedControlMessage { kind })
+                    }
+                    Err(e) => Err(e),
+                };
+                let processed_body = match opened {
+                    Ok(message) => message,
+                    // A single response cannot be dropped without leaving the request unanswered
+                    Err(e) => match (self.settings.open_failure_policy, request_id) {
+                        (OpenFailurePolicy::JsonRpcError, Some(request_id)) => {
+                            log::error!("failed to open message: {}", e);
+                            let error = open_failure_error(&e, request_id);
+                            return Ok(StreamableHttpPostResponse::Json(error, session_id));
+                        }
+                        _ => return Err(StreamableHttpError::Client(e)),
+                    },
+                };
 
-                let message: ServerJsonRpcMessage = serde_json::from_str(&processed_body)
+                let message: ServerJsonRpcMessage = serde_json::from_str(&processed_body.payload)
                     .map_err(StreamableHttpError::Deserialize)?;
                 Ok(StreamableHttpPostResponse::Json(message, session_id))
             }
@@ -217,6 +481,113 @@ impl TmcpClient {
     
         StreamableHttpClientTransport::with_client(self.clone(), config)
     }
+
+    /// Create a stdio style transport over `reader` and `writer`, sealing every frame for `other_did`.
+    ///
+    /// Works for either side of the connection: a server launched as a subprocess can build its own
+    /// `TmcpClient` with the client's DID as `other_did` and wrap its stdin and stdout.
+    pub fn create_stdio_transport<R, Rd, Wr>(
+        &self,
+        reader: Rd,
+        writer: Wr,
+    ) -> (transport::TmcpSink<R>, transport::TmcpStream<R>)
+    where
+        R: rmcp::service::ServiceRole,
+        rmcp::service::TxJsonRpcMessage<R>: serde::Serialize,
+        rmcp::service::RxJsonRpcMessage<R>: serde::de::DeserializeOwned,
+        Rd: tokio::io::AsyncRead + Send + 'static,
+        Wr: tokio::io::AsyncWrite + Send + 'static,
+    {
+        stdio::transport(
+            self.wallet.clone(),
+            self.my_did.clone(),
+            self.other_did(),
+            reader,
+            writer,
+            self.settings.open_failure_policy,
+        )
+    }
+
+    /// Connect to a TMCP WebSocket server at `url`; every binary frame is a TSP message sealed for `other_did`.
+    pub async fn connect_websocket(
+        &self,
+        url: &str,
+    ) -> Result<
+        (
+            transport::TmcpSink<rmcp::RoleClient>,
+            transport::TmcpStream<rmcp::RoleClient>,
+        ),
+        TmcpError,
+    > {
+        websocket::connect(
+            self.wallet.clone(),
+            self.my_did.clone(),
+            self.other_did(),
+            url,
+            self.settings.open_failure_policy,
+        )
+        .await
+    }
+
+    /// Create a transport that sends sealed messages straight to the TSP endpoint published in
+    /// `other_did` (e.g. `tcp://`), and receives on the endpoint of our own DID, bypassing HTTP.
+    pub async fn create_tcp_transport<R>(
+        &self,
+    ) -> Result<(transport::TmcpSink<R>, transport::TmcpStream<R>), TmcpError>
+    where
+        R: rmcp::service::ServiceRole,
+        rmcp::service::TxJsonRpcMessage<R>: serde::Serialize,
+        rmcp::service::RxJsonRpcMessage<R>: serde::de::DeserializeOwned,
+    {
+        tcp::transport(
+            self.wallet.clone(),
+            self.my_did.clone(),
+            self.other_did(),
+            self.settings.open_failure_policy,
+        )
+        .await
+    }
+
+    /// Spawn `command` as an MCP server and talk to it over its stdin and stdout.
+    ///
+    /// The child process is killed once the returned stream is dropped.
+    pub fn spawn_stdio_transport(
+        &self,
+        mut command: tokio::process::Command,
+    ) -> Result<
+        (
+            transport::TmcpSink<rmcp::RoleClient>,
+            transport::TmcpStream<rmcp::RoleClient>,
+        ),
+        TmcpError,
+    > {
+        use std::process::Stdio;
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let (Some(stdin), Some(stdout)) = (child.stdin.take(), child.stdout.take()) else {
+            return Err(TmcpError::TmcpError("child process has no stdio".into()));
+        };
+        let (sink, stream) = self.create_stdio_transport(stdout, stdin);
+        // Keep the child alive for as long as messages are read from it
+        let stream = stream
+            .map(move |message| {
+                let _child = &child;
+                message
+            })
+            .boxed();
+        Ok((sink, stream))
+    }
+}
+
+/// The JSON-RPC error answering `request_id` when the response to it cannot be opened
+fn open_failure_error(e: &TmcpError, request_id: RequestId) -> ServerJsonRpcMessage {
+    ServerJsonRpcMessage::error(
+        ErrorData::internal_error(format!("unable to open TSP message: {e}"), None),
+        request_id,
+    )
 }
 
 impl StreamableHttpClient for TmcpClient {
@@ -229,19 +600,24 @@ impl StreamableHttpClient for TmcpClient {
         session_id: Option<Arc<str>>,
         auth_token: Option<String>,
     ) -> Result<StreamableHttpPostResponse, StreamableHttpError<Self::Error>> {
+        let request_id = match &message {
+            ClientJsonRpcMessage::Request(request) => Some(request.id.clone()),
+            _ => None,
+        };
+        *self.endpoint.write().expect("endpoint lock poisoned") = Some((uri.clone(), auth_token.clone()));
+        if self.settings.relationship_handshake {
+            self.ensure_relationship(&uri, auth_token.as_deref())
+                .await
+                .map_err(StreamableHttpError::Client)?;
+        }
         // Apply TSP seal_message transformation if transport hook is available
         let message_to_send = {
             let json_str =
                 serde_json::to_string(&message).map_err(StreamableHttpError::Deserialize)?;
             // Use the transport hook to seal the message
-            let sealed_data = tsp_messages::seal_message(json_str, &self.wallet, &self.my_did, &self.other_did);
-            let sealed_data = match sealed_data {
-                Ok(s) => s,
-                Err(e) => {
-                    log::error!("failed to seal message: {}", e);
-                    "".to_string()
-                }
-            };
+            // Never fall back to sending the message unsealed
+            let sealed_data = tsp_messages::seal_message(json_str, &self.wallet, &self.my_did, &self.other_did())
+                .map_err(StreamableHttpError::Client)?;
             // Try to parse the sealed data back to a message, or use raw body
             match serde_json::from_str::<ClientJsonRpcMessage>(&sealed_data) {
                 Ok(sealed_message) => sealed_message,
@@ -266,7 +642,7 @@ impl StreamableHttpClient for TmcpClient {
                         .await
                         .map_err(|e| StreamableHttpError::Client(TmcpError::Reqwest(e)))?;
 
-                    return self.handle_response(response).await;
+                    return self.handle_response(response, request_id).await;
                 }
             }
         };
@@ -290,7 +666,7 @@ impl StreamableHttpClient for TmcpClient {
             .await
             .map_err(|e| StreamableHttpError::Client(TmcpError::Reqwest(e)))?;
 
-        self.handle_response(response).await
+        self.handle_response(response, request_id).await
     }
 
     /// Get SSE stream from the server
@@ -332,25 +708,8 @@ impl StreamableHttpClient for TmcpClient {
                 return Err(StreamableHttpError::UnexpectedContentType(None));
             }
         }
-        let event_stream = SseStream::from_byte_stream(response.bytes_stream()).boxed();
-        let wallet_clone = self.wallet.clone();
-        let wrapped_stream = event_stream.map(move |result| {
-            result.map(|mut sse| {
-                if let Some(data) = sse.data.take() {
-                    let processed_data = tsp_messages::open_message(data, &wallet_clone);
-                    match processed_data {
-                        Ok(processed_data) => {
-                            sse.data = Some(processed_data);
-                        }
-                        Err(e) => {
-                            log::error!("failed to open message: {}", e);
-                        }
-                    }
-                }
-                sse
-            })
-        });
-        Ok(wrapped_stream.boxed())
+        let event_stream = SseStream::from_byte_stream(response.bytes_stream());
+        Ok(self.open_sse_stream(event_stream, None))
     }
 
     async fn delete_session(
@@ -360,7 +719,7 @@ impl StreamableHttpClient for TmcpClient {
         auth_token: Option<String>,
     ) -> Result<(), StreamableHttpError<Self::Error>> {
         let mut request_builder = self.inner.delete(uri.as_ref());
-        if let Some(auth_header) = auth_token {
+        if let Some(auth_header) = &auth_token {
             request_builder = request_builder.bearer_auth(auth_header);
         }
         let response = request_builder
@@ -371,11 +730,16 @@ impl StreamableHttpClient for TmcpClient {
 
         if response.status() == reqwest::StatusCode::METHOD_NOT_ALLOWED {
             log::debug!("this server doesn't support deleting session");
-            return Ok(());
+        } else {
+            let _response = response
+                .error_for_status()
+                .map_err(|e| StreamableHttpError::Client(TmcpError::Reqwest(e)))?;
+        }
+        if self.settings.cancel_relationship_on_close {
+            self.cancel_relationship(&uri, auth_token.as_deref())
+                .await
+                .map_err(StreamableHttpError::Client)?;
         }
-        let _response = response
-            .error_for_status()
-            .map_err(|e| StreamableHttpError::Client(TmcpError::Reqwest(e)))?;
         Ok(())
     }
 }
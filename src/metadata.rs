@@ -0,0 +1,153 @@
+//! TMCP bookkeeping kept next to the wallet.
+//!
+//! Which of our DIDs are retired or have a key rotation pending, and what we last saw of our peers
+//! (their did:webvh version and keys, and the new identifiers they announced) is kept in a JSON file
+//! next to the wallet, `<wallet>.tmcp.json`, so that the wallet itself only holds VIDs, aliases and
+//! keys. The keys of a pending rotation stay in the wallet; this store only marks them pending.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{errors::TmcpError, pins::Pin, settings::TmcpSettings, storage};
+
+/// What we last saw of a peer
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PeerRecord {
+    /// Last did:webvh log version
+    pub version_id: String,
+    #[serde(flatten)]
+    pub keys: Pin,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct Metadata {
+    /// Our DIDs that must never be used again
+    retired: BTreeSet<String>,
+    /// Our DIDs with rotated keys in the wallet that may not be published yet
+    pending_rotations: BTreeSet<String>,
+    /// Peer records by DID
+    peers: BTreeMap<String, PeerRecord>,
+    /// The new identifier each peer DID announced
+    identifiers: BTreeMap<String, String>,
+}
+
+/// TMCP bookkeeping, kept in a JSON file (or only in memory)
+#[derive(Debug, Default)]
+pub struct MetadataStore {
+    path: Option<PathBuf>,
+    metadata: Mutex<Metadata>,
+}
+
+impl MetadataStore {
+    /// Opens the metadata file at `path`, which is created on the first change
+    #[allow(clippy::result_large_err)]
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, TmcpError> {
+        let path = path.into();
+        let metadata = match std::fs::read(&path) {
+            Ok(metadata) => serde_json::from_slice(&metadata)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Metadata::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            path: Some(path),
+            metadata: Mutex::new(metadata),
+        })
+    }
+
+    /// A metadata store that is not saved, e.g. for in-memory wallets
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    /// Opens the metadata store of the wallet selected in `settings`, `<wallet>.tmcp.json`
+    #[allow(clippy::result_large_err)]
+    pub fn from_settings(settings: &TmcpSettings) -> Result<Self, TmcpError> {
+        match storage::sidecar_path(settings, ".tmcp.json") {
+            Some(path) => Self::open(path),
+            None => Ok(Self::in_memory()),
+        }
+    }
+
+    /// Whether our `did` was retired
+    pub(crate) fn is_retired(&self, did: &str) -> bool {
+        self.lock().retired.contains(did)
+    }
+
+    /// Marks our `did` as retired, so that it is never used again
+    #[allow(clippy::result_large_err)]
+    pub(crate) fn retire(&self, did: &str) -> Result<(), TmcpError> {
+        self.update(|metadata| {
+            metadata.retired.insert(did.to_string());
+        })
+    }
+
+    /// Whether a key rotation of our `did` is pending
+    pub(crate) fn has_pending_rotation(&self, did: &str) -> bool {
+        self.lock().pending_rotations.contains(did)
+    }
+
+    /// Marks a key rotation of our `did` as pending, or as completed
+    #[allow(clippy::result_large_err)]
+    pub(crate) fn set_pending_rotation(&self, did: &str, pending: bool) -> Result<(), TmcpError> {
+        self.update(|metadata| {
+            if pending {
+                metadata.pending_rotations.insert(did.to_string());
+            } else {
+                metadata.pending_rotations.remove(did);
+            }
+        })
+    }
+
+    /// What we last saw of the peer `did`
+    pub(crate) fn peer(&self, did: &str) -> Option<PeerRecord> {
+        self.lock().peers.get(did).cloned()
+    }
+
+    /// Records what we saw of the peer `did`
+    #[allow(clippy::result_large_err)]
+    pub(crate) fn set_peer(&self, did: &str, record: PeerRecord) -> Result<(), TmcpError> {
+        self.update(|metadata| {
+            metadata.peers.insert(did.to_string(), record);
+        })
+    }
+
+    /// The new identifier the peer `did` announced
+    pub(crate) fn identifier(&self, did: &str) -> Option<String> {
+        self.lock().identifiers.get(did).cloned()
+    }
+
+    /// Records that the peer `previous` is now known as `new_did`
+    #[allow(clippy::result_large_err)]
+    pub(crate) fn set_identifier(&self, previous: &str, new_did: &str) -> Result<(), TmcpError> {
+        self.update(|metadata| {
+            metadata.identifiers.insert(previous.to_string(), new_did.to_string());
+            // A peer switching back must not be forwarded to the identifier it left
+            metadata.identifiers.remove(new_did);
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Metadata> {
+        self.metadata.lock().expect("metadata lock poisoned")
+    }
+
+    /// Applies `change` and saves the result
+    #[allow(clippy::result_large_err)]
+    fn update(&self, change: impl FnOnce(&mut Metadata)) -> Result<(), TmcpError> {
+        let mut metadata = self.lock();
+        change(&mut metadata);
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, serde_json::to_vec_pretty(&*metadata)?)?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
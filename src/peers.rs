@@ -0,0 +1,131 @@
+//! Tracking of the peers we talk to.
+//!
+//! On every connect the did:webvh log of a peer is verified as a whole, and the last seen version and
+//! keys are kept in the [`MetadataStore`] next to the wallet. A log that no longer contains the
+//! version seen before, or keys that changed without a new log entry, are reported according to
+//! [`PeerHistoryPolicy`], unless the new keys were accepted through the pin store (a mismatch
+//! callback or [`repin`](crate::pins::repin)). Other DIDs have no history to check; their keys are
+//! only guarded by the pin store.
+
+use tsp_sdk::{AsyncSecureStore, VerifiedVid, Vid};
+
+use crate::{
+    errors::TmcpError,
+    metadata::{MetadataStore, PeerRecord},
+    pins::{Pin, PinMismatchCallback, PinStore},
+    resolver::DidResolver,
+    settings::{PeerHistoryPolicy, TmcpSettings},
+    verify, webvh,
+};
+
+/// The identifier the peer `did` currently uses, following the new identifiers it announced
+pub(crate) fn current_identifier(metadata: &MetadataStore, did: &str) -> String {
+    let mut seen = vec![did.to_string()];
+    while let Some(next) = metadata
+        .identifier(seen.last().expect("seen is never empty"))
+        .filter(|next| !seen.contains(next))
+    {
+        seen.push(next);
+    }
+    seen.pop().expect("seen is never empty")
+}
+
+/// Checks the peer `did` before we talk to it: the trust policy, its pinned keys and its did:webvh
+/// history. Its verified keys are then added to the wallet.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn admit(
+    did: &str,
+    wallet: &AsyncSecureStore,
+    metadata: &MetadataStore,
+    settings: &TmcpSettings,
+    client: &reqwest::Client,
+    resolver: &dyn DidResolver,
+    pin_store: &PinStore,
+    on_pin_mismatch: Option<&PinMismatchCallback>,
+) -> Result<(), TmcpError> {
+    settings.trust_policy.check(did)?;
+    let (vid, vid_metadata) = resolver.resolve(did).await?;
+    // Keys accepted by the mismatch callback are not a history violation
+    if pin_store.check(did, Pin::of(&vid), on_pin_mismatch)? {
+        accept_keys(&vid, metadata)?;
+    }
+    check_peer(&vid, metadata, settings, client, resolver).await?;
+    verify::store_verified(vid, vid_metadata, wallet, None)?;
+    Ok(())
+}
+
+/// Verifies the did:webvh history of the resolved peer `vid` against what we saw before, and records
+/// the current state.
+pub(crate) async fn check_peer(
+    vid: &Vid,
+    metadata: &MetadataStore,
+    settings: &TmcpSettings,
+    client: &reqwest::Client,
+    resolver: &dyn DidResolver,
+) -> Result<(), TmcpError> {
+    let did = vid.identifier();
+    if matches!(settings.peer_history_policy, PeerHistoryPolicy::Off) || !did.starts_with("did:webvh:") {
+        return Ok(());
+    }
+    let log = match resolver.resolve_log(did, client, settings).await {
+        Ok(log) => log,
+        // Without the log there is nothing to check the history against
+        Err(error) => return report(settings, error),
+    };
+    if let Err(reason) = webvh::verify_log(did, &log) {
+        report(settings, TmcpError::PeerLogInvalid { did: did.to_string(), reason })?;
+    }
+    if webvh::active_parameters(&log).get("deactivated") == Some(&serde_json::Value::Bool(true)) {
+        return Err(TmcpError::RetiredIdentity(did.to_string()));
+    }
+
+    let current = PeerRecord {
+        version_id: log
+            .last()
+            .and_then(|entry| entry["versionId"].as_str())
+            .unwrap_or_default()
+            .to_string(),
+        keys: Pin::of(vid),
+    };
+    if let Some(previous) = metadata.peer(did) {
+        if !log.iter().any(|entry| entry["versionId"].as_str() == Some(&previous.version_id)) {
+            report(
+                settings,
+                TmcpError::PeerLogRewritten {
+                    did: did.to_string(),
+                    version_id: previous.version_id.clone(),
+                },
+            )?;
+        }
+        if previous.keys != current.keys && previous.version_id == current.version_id {
+            report(settings, TmcpError::PeerKeysChanged(did.to_string()))?;
+        }
+    }
+
+    metadata.set_peer(did, current)
+}
+
+/// Replaces the keys recorded for the peer `vid` with its current ones, after they were accepted
+/// explicitly
+#[allow(clippy::result_large_err)]
+pub(crate) fn accept_keys(vid: &Vid, metadata: &MetadataStore) -> Result<(), TmcpError> {
+    match metadata.peer(vid.identifier()) {
+        Some(mut record) => {
+            record.keys = Pin::of(vid);
+            metadata.set_peer(vid.identifier(), record)
+        }
+        None => Ok(()),
+    }
+}
+
+/// Fails with `error` or logs it, depending on the policy
+#[allow(clippy::result_large_err)]
+fn report(settings: &TmcpSettings, error: TmcpError) -> Result<(), TmcpError> {
+    match settings.peer_history_policy {
+        PeerHistoryPolicy::Enforce => Err(error),
+        PeerHistoryPolicy::Warn | PeerHistoryPolicy::Off => {
+            log::warn!("{}", error);
+            Ok(())
+        }
+    }
+}
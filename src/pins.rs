@@ -0,0 +1,171 @@
+//! Trust-on-first-use pinning of peer keys.
+//!
+//! The verification and encryption keys first resolved for an `other_did` are pinned in a JSON file
+//! next to the wallet. When a client later resolves different keys, it refuses to start unless a
+//! mismatch callback accepts them (see [`TmcpClientBuilder::on_pin_mismatch`](crate::TmcpClientBuilder::on_pin_mismatch)).
+//! Legitimate key changes are accepted explicitly with [`repin`].
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use base64::{Engine as _, engine::general_purpose};
+use serde::{Deserialize, Serialize};
+use tsp_sdk::VerifiedVid;
+
+use crate::{
+    errors::TmcpError,
+    metadata::MetadataStore,
+    peers,
+    resolver::{DidResolver, DidServerResolver},
+    settings::TmcpSettings,
+    storage,
+};
+
+/// Callback deciding whether keys differing from the pinned ones are accepted (and re-pinned)
+pub type PinMismatchCallback = Arc<dyn Fn(&str, &Pin, &Pin) -> bool + Send + Sync>;
+
+/// Keys pinned for a DID, URL-safe base64
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Pin {
+    pub verifying_key: String,
+    pub encryption_key: String,
+}
+
+impl Pin {
+    /// The keys of `vid`
+    pub fn of(vid: &impl VerifiedVid) -> Self {
+        Self {
+            verifying_key: general_purpose::URL_SAFE.encode(&vid.verifying_key()[..]),
+            encryption_key: general_purpose::URL_SAFE.encode(&vid.encryption_key()[..]),
+        }
+    }
+}
+
+/// Pinned keys by DID, kept in a JSON file (or only in memory)
+#[derive(Debug, Default)]
+pub struct PinStore {
+    path: Option<PathBuf>,
+    pins: Mutex<HashMap<String, Pin>>,
+}
+
+impl PinStore {
+    /// Opens the pin file at `path`, which is created on the first pin
+    #[allow(clippy::result_large_err)]
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, TmcpError> {
+        let path = path.into();
+        let pins = match std::fs::read(&path) {
+            Ok(pins) => serde_json::from_slice(&pins)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            path: Some(path),
+            pins: Mutex::new(pins),
+        })
+    }
+
+    /// A pin store that is not saved, e.g. for in-memory wallets
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    /// Opens the pin store configured in `settings`, by default `<wallet>.pins.json` next to the wallet
+    #[allow(clippy::result_large_err)]
+    pub fn from_settings(settings: &TmcpSettings) -> Result<Self, TmcpError> {
+        if let Some(path) = &settings.pin_store_path {
+            return Self::open(path);
+        }
+        match storage::sidecar_path(settings, ".pins.json") {
+            Some(path) => Self::open(path),
+            None => Ok(Self::in_memory()),
+        }
+    }
+
+    /// The keys pinned for `did`
+    pub fn get(&self, did: &str) -> Option<Pin> {
+        self.pins.lock().expect("pin lock poisoned").get(did).cloned()
+    }
+
+    /// Pins `pin` for `did`, replacing an earlier pin
+    #[allow(clippy::result_large_err)]
+    pub fn pin(&self, did: &str, pin: Pin) -> Result<(), TmcpError> {
+        let mut pins = self.pins.lock().expect("pin lock poisoned");
+        pins.insert(did.to_string(), pin);
+        self.save(&pins)
+    }
+
+    /// Forgets the pin of `did`, so that its keys are pinned again on the next connect
+    #[allow(clippy::result_large_err)]
+    pub fn unpin(&self, did: &str) -> Result<(), TmcpError> {
+        let mut pins = self.pins.lock().expect("pin lock poisoned");
+        pins.remove(did);
+        self.save(&pins)
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn save(&self, pins: &HashMap<String, Pin>) -> Result<(), TmcpError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, serde_json::to_vec_pretty(pins)?)?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Pins the keys of `did` on first use; afterwards checks they are unchanged.
+    ///
+    /// Different keys are accepted (and pinned) only if `on_mismatch` returns `true`; returns
+    /// whether that happened.
+    #[allow(clippy::result_large_err)]
+    pub(crate) fn check(
+        &self,
+        did: &str,
+        resolved: Pin,
+        on_mismatch: Option<&PinMismatchCallback>,
+    ) -> Result<bool, TmcpError> {
+        match self.get(did) {
+            None => {
+                log::info!("pinned keys of {}", did);
+                self.pin(did, resolved)?;
+                Ok(false)
+            }
+            Some(pinned) if pinned == resolved => Ok(false),
+            Some(pinned) => {
+                if on_mismatch.is_some_and(|accept| accept(did, &pinned, &resolved)) {
+                    log::warn!("accepted new keys of {}", did);
+                    self.pin(did, resolved)?;
+                    Ok(true)
+                } else {
+                    Err(TmcpError::PinMismatch(did.to_string()))
+                }
+            }
+        }
+    }
+}
+
+/// Resolves `did` and pins its current keys in the pin store configured in `settings`.
+///
+/// The keys are also accepted in the peer history kept next to the wallet selected in `settings`, so
+/// that a key change without a new did:webvh log entry is not refused either. Stop clients using
+/// the wallet first, they would overwrite both files with what they saw.
+pub async fn repin(did: &str, settings: &TmcpSettings) -> Result<Pin, TmcpError> {
+    repin_with_resolver(did, settings, &DidServerResolver::from_settings(settings)?).await
+}
+
+/// Like [`repin`], resolving `did` with `resolver`
+pub async fn repin_with_resolver(
+    did: &str,
+    settings: &TmcpSettings,
+    resolver: &dyn DidResolver,
+) -> Result<Pin, TmcpError> {
+    let (vid, _) = resolver.resolve(did).await?;
+    let pin = Pin::of(&vid);
+    peers::accept_keys(&vid, &MetadataStore::from_settings(settings)?)?;
+    PinStore::from_settings(settings)?.pin(did, pin.clone())?;
+    Ok(pin)
+}
@@ -0,0 +1,196 @@
+//! DID resolution.
+//!
+//! TMCP resolves DIDs through a [`DidResolver`], so that the online resolution done by tsp_sdk can be
+//! swapped for a hermetic one, e.g. in air-gapped CI.
+
+use std::{collections::HashMap, path::Path, sync::RwLock};
+
+use futures::future::BoxFuture;
+use serde_json::Value;
+use tsp_sdk::{
+    VerifiedVid, Vid,
+    vid::did::web::{DidDocument, resolve_document},
+};
+
+use crate::{errors::TmcpError, settings::TmcpSettings, webvh};
+
+/// Resolves a DID to its verified public key material.
+pub trait DidResolver: Send + Sync {
+    /// Resolve and verify `did`, returning the VID and its (optional) metadata.
+    fn resolve<'a>(&'a self, did: &'a str) -> BoxFuture<'a, Result<(Vid, Option<Value>), TmcpError>>;
+
+    /// Fetch the did:webvh log of `did`, one JSON value per entry.
+    ///
+    /// By default the log is downloaded from the host named in the DID:
+    /// `did:webvh:{scid}:{domain}:{path}` is published at `https://{domain}/{path}/did.jsonl`.
+    fn resolve_log<'a>(
+        &'a self,
+        did: &'a str,
+        client: &'a reqwest::Client,
+        settings: &'a TmcpSettings,
+    ) -> BoxFuture<'a, Result<Vec<Value>, TmcpError>> {
+        Box::pin(webvh::fetch_log(client, settings, did))
+    }
+}
+
+/// Resolves DIDs online with `tsp_sdk::vid::verify_vid`, always over HTTPS.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NetworkResolver;
+
+impl DidResolver for NetworkResolver {
+    fn resolve<'a>(&'a self, did: &'a str) -> BoxFuture<'a, Result<(Vid, Option<Value>), TmcpError>> {
+        Box::pin(async move { Ok(tsp_sdk::vid::verify_vid(did).await?) })
+    }
+}
+
+/// Resolves DIDs published on the DID server of the settings through that server, honouring
+/// `did_server_scheme` and the DID server TLS settings; other DIDs are resolved with
+/// [`NetworkResolver`]. This is the default resolver of clients and servers.
+#[derive(Debug, Clone)]
+pub struct DidServerResolver {
+    settings: TmcpSettings,
+    client: reqwest::Client,
+}
+
+impl DidServerResolver {
+    /// Reaches the DID server of `settings` with `client`, see [`TmcpSettings::did_server_client`]
+    pub fn new(settings: &TmcpSettings, client: reqwest::Client) -> Self {
+        Self {
+            settings: settings.clone(),
+            client,
+        }
+    }
+
+    /// Reaches the DID server of `settings` with [`TmcpSettings::did_server_client`]
+    #[allow(clippy::result_large_err)]
+    pub fn from_settings(settings: &TmcpSettings) -> Result<Self, TmcpError> {
+        Ok(Self::new(settings, settings.did_server_client()?))
+    }
+}
+
+impl DidResolver for DidServerResolver {
+    fn resolve<'a>(&'a self, did: &'a str) -> BoxFuture<'a, Result<(Vid, Option<Value>), TmcpError>> {
+        Box::pin(async move {
+            let url = match published_url(&self.settings, did, "did.json") {
+                Some((url, true)) => url,
+                _ => return NetworkResolver.resolve(did).await,
+            };
+            let document = if did.starts_with("did:webvh:") {
+                let log = webvh::fetch_log(&self.client, &self.settings, did).await?;
+                webvh::verify_log(did, &log)
+                    .map_err(|reason| TmcpError::WebVh(format!("invalid log of {did}: {reason}")))?;
+                webvh::last_state(&log)
+            } else {
+                self.client.get(url).send().await?.error_for_status()?.json().await?
+            };
+            let document: DidDocument = serde_json::from_value(document)?;
+            Ok((resolve_document(document, did)?, None))
+        })
+    }
+}
+
+/// Where the DID document (`file` = `did.json`) or log (`did.jsonl`) of a did:web or did:webvh is
+/// published, and whether that is on the DID server of `settings`.
+///
+/// `did:web:{domain}:{path}` and `did:webvh:{scid}:{domain}:{path}` are published at
+/// `https://{domain}/{path}/{file}`, or `https://{domain}/.well-known/{file}` without a path. The
+/// DID server is reached with `did_server_scheme` instead.
+pub(crate) fn published_url(settings: &TmcpSettings, did: &str, file: &str) -> Option<(String, bool)> {
+    let location = match did.strip_prefix("did:webvh:") {
+        Some(rest) => rest.split_once(':')?.1,
+        None => did.strip_prefix("did:web:")?,
+    };
+    let mut segments = location.split(':');
+    let domain = segments
+        .next()
+        .filter(|domain| !domain.is_empty())?
+        .replace("%3A", ":")
+        .replace("%3a", ":");
+    let path: Vec<&str> = segments.collect();
+    let location = std::iter::once(domain.as_str())
+        .chain(path.iter().copied())
+        .collect::<Vec<_>>()
+        .join("/");
+    let on_did_server = format!("{location}/").starts_with(&format!("{}/", settings.did_server));
+    let scheme = if on_did_server {
+        settings.did_server_scheme.as_str()
+    } else {
+        "https"
+    };
+    let path = if path.is_empty() {
+        ".well-known".to_string()
+    } else {
+        path.join("/")
+    };
+    Some((format!("{scheme}://{domain}/{path}/{file}"), on_did_server))
+}
+
+/// Resolves DIDs from a fixed set of VIDs and did:webvh logs, without any network access.
+#[derive(Debug, Default)]
+pub struct InMemoryResolver {
+    vids: RwLock<HashMap<String, (Vid, Option<Value>)>>,
+    logs: RwLock<HashMap<String, Vec<Value>>>,
+}
+
+impl InMemoryResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads a fixture file holding a JSON array of VIDs, in the format the DID server returns from `/add-vid`.
+    #[allow(clippy::result_large_err)]
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, TmcpError> {
+        let fixture = std::fs::read_to_string(path)?;
+        let vids: Vec<Vid> = serde_json::from_str(&fixture)?;
+        let resolver = Self::new();
+        for vid in vids {
+            resolver.insert(vid, None);
+        }
+        Ok(resolver)
+    }
+
+    /// Adds (or replaces) the VID `did` resolves to
+    pub fn insert(&self, vid: Vid, metadata: Option<Value>) {
+        self.vids
+            .write()
+            .expect("resolver lock poisoned")
+            .insert(vid.identifier().to_string(), (vid, metadata));
+    }
+
+    /// Sets (or replaces) the did:webvh log of `did`
+    pub fn insert_log(&self, did: &str, log: Vec<Value>) {
+        self.logs
+            .write()
+            .expect("resolver lock poisoned")
+            .insert(did.to_string(), log);
+    }
+}
+
+impl DidResolver for InMemoryResolver {
+    fn resolve<'a>(&'a self, did: &'a str) -> BoxFuture<'a, Result<(Vid, Option<Value>), TmcpError>> {
+        let resolved = self
+            .vids
+            .read()
+            .expect("resolver lock poisoned")
+            .get(did)
+            .cloned()
+            .ok_or_else(|| TmcpError::UnresolvedDid(did.to_string()));
+        Box::pin(async move { resolved })
+    }
+
+    fn resolve_log<'a>(
+        &'a self,
+        did: &'a str,
+        _client: &'a reqwest::Client,
+        _settings: &'a TmcpSettings,
+    ) -> BoxFuture<'a, Result<Vec<Value>, TmcpError>> {
+        let log = self
+            .logs
+            .read()
+            .expect("resolver lock poisoned")
+            .get(did)
+            .cloned()
+            .ok_or_else(|| TmcpError::UnresolvedDid(did.to_string()));
+        Box::pin(async move { log })
+    }
+}
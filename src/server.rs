@@ -0,0 +1,73 @@
+//! Server side of TMCP.
+//!
+//! [`TmcpServer`] wraps rmcp's [`StreamableHttpService`] with a [`TmcpLayer`]: incoming request
+//! bodies are opened as TSP messages before they reach rmcp, and JSON and SSE responses are sealed
+//! back to the caller's DID.
+
+use std::task::{Context, Poll};
+
+use http::Request;
+use rmcp::transport::streamable_http_server::{
+    StreamableHttpService, session::local::LocalSessionManager,
+};
+use tower_layer::Layer;
+
+use crate::{
+    errors::TmcpError,
+    identity::ServerIdentity,
+    layer::{TmcpLayer, TmcpService},
+    settings::TmcpSettings,
+};
+
+/// An rmcp streamable HTTP service that speaks TSP with its callers.
+#[derive(Clone)]
+pub struct TmcpServer<S, M = LocalSessionManager> {
+    service: TmcpService<StreamableHttpService<S, M>>,
+}
+
+impl<S, M> TmcpServer<S, M> {
+    /// Loads (or provisions) the server identity for `alias`, see [`TmcpLayer::new`], and wraps
+    /// `service` with it.
+    pub async fn new(
+        alias: &str,
+        settings: TmcpSettings,
+        service: StreamableHttpService<S, M>,
+    ) -> Result<Self, TmcpError> {
+        let layer = TmcpLayer::new(alias, settings).await?;
+        Ok(Self::from_layer(&layer, service))
+    }
+
+    /// Wraps `service` with the identity of an already loaded `layer`
+    pub fn from_layer(layer: &TmcpLayer, service: StreamableHttpService<S, M>) -> Self {
+        Self {
+            service: layer.layer(service),
+        }
+    }
+
+    /// The identity callers talk to
+    pub fn identity(&self) -> &ServerIdentity {
+        self.service.identity()
+    }
+
+    /// Saves pending wallet changes now, see [`ServerIdentity::flush`]
+    pub async fn flush(&self) -> Result<(), TmcpError> {
+        self.service.identity().flush().await
+    }
+}
+
+impl<S, M, B> tower_service::Service<Request<B>> for TmcpServer<S, M>
+where
+    TmcpService<StreamableHttpService<S, M>>: tower_service::Service<Request<B>>,
+{
+    type Response = <TmcpService<StreamableHttpService<S, M>> as tower_service::Service<Request<B>>>::Response;
+    type Error = <TmcpService<StreamableHttpService<S, M>> as tower_service::Service<Request<B>>>::Error;
+    type Future = <TmcpService<StreamableHttpService<S, M>> as tower_service::Service<Request<B>>>::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<B>) -> Self::Future {
+        self.service.call(request)
+    }
+}
@@ -1,4 +1,10 @@
+use std::time::Duration;
+
+use reqwest::Url;
 use serde::{Deserialize, Serialize};
+
+use crate::errors::TmcpError;
+
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub enum DidType {
     #[default]
@@ -6,60 +12,418 @@ pub enum DidType {
     Peer,
     Webvh,
 }
+/// Backend persisting the wallet
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub enum WalletType {
+    /// Askar database, `wallet_url` is e.g. `sqlite://wallet.sqlite`
+    #[default]
+    Askar,
+    /// Kept in memory only, `wallet_url` is ignored
+    InMemory,
+    /// Single JSON file encrypted with `wallet_password`, `wallet_url` is its path
+    EncryptedFile,
+}
+/// How unexpected changes in a peer's DID history are handled
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub enum PeerHistoryPolicy {
+    /// Refuse the peer with a typed error
+    #[default]
+    Enforce,
+    /// Log a warning and accept the peer
+    Warn,
+    /// Do not check the history
+    Off,
+}
+/// What happens to an incoming message that cannot be opened as TSP, e.g. plaintext injected
+/// into a stream. It never reaches rmcp.
+#[derive(Default, Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum OpenFailurePolicy {
+    /// Log and drop the message
+    #[default]
+    Drop,
+    /// End the stream (an SSE stream ends with an error)
+    FailStream,
+    /// Answer the request in flight with a JSON-RPC error; where no request is in flight (GET
+    /// streams, stdio, WebSocket and TCP transports) the message is dropped
+    JsonRpcError,
+}
+
+/// Which peer DIDs may be talked to.
+///
+/// Entries are DIDs in which `*` matches any characters within one `:` separated segment, and a
+/// final `**` segment matches any remaining segments: `did:webvh:*:example.com:**` allows any
+/// did:webvh on `example.com` (but not `did:webvh:*:evil.org:example.com:…`), `did:web:**` any
+/// did:web. Deny entries win over allow entries; an empty allow list allows every DID that is not
+/// denied.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TrustPolicy {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+impl TrustPolicy {
+    /// Whether `did` may be talked to
+    pub fn allows(&self, did: &str) -> bool {
+        !self.deny.iter().any(|pattern| matches_pattern(pattern, did))
+            && (self.allow.is_empty() || self.allow.iter().any(|pattern| matches_pattern(pattern, did)))
+    }
+
+    /// Fails with [`TmcpError::UntrustedPeer`] unless `did` may be talked to
+    #[allow(clippy::result_large_err)]
+    pub fn check(&self, did: &str) -> Result<(), TmcpError> {
+        if self.allows(did) {
+            Ok(())
+        } else {
+            Err(TmcpError::UntrustedPeer(did.to_string()))
+        }
+    }
+}
+
+/// Matches `did` against `pattern` segment by segment, a final `**` matches the remaining segments
+fn matches_pattern(pattern: &str, did: &str) -> bool {
+    let mut segments = did.split(':');
+    let mut patterns = pattern.split(':').peekable();
+    while let Some(pattern) = patterns.next() {
+        if pattern == "**" && patterns.peek().is_none() {
+            return true;
+        }
+        match segments.next() {
+            Some(segment) if matches_segment(pattern, segment) => {}
+            _ => return false,
+        }
+    }
+    segments.next().is_none()
+}
+
+/// Matches one DID segment against `pattern`, in which `*` matches any characters
+fn matches_segment(pattern: &str, segment: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let Some(mut rest) = segment.strip_prefix(parts.next().unwrap_or_default()) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
 /// TMCP general settings
+///
+/// The DID server templates may use the placeholders `{scheme}` (the `did_server_scheme` setting),
+/// `{server}` (the `did_server` setting), `{name}` (the username a DID is published under) and
+/// `{did}` (the DID itself).
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct TmcpSettings {
-    /// DID publish URL
+    /// DID publish URL template
     pub did_publish_url: String,
     /// DID publish history URL template
     pub did_publish_history_url: String,
-    /// DID web format template
+    /// DID web format template, `{server}` is percent-encoded as required by did:web
     pub did_web_format: String,
-    /// DID webvh format template
+    /// DID webvh format template (domain and path of the DID)
     pub did_webvh_format: String,
-    /// Transport protocol prefix
+    /// URL template of the published DID log, used to look up existing DIDs
+    pub did_lookup_url: String,
+    /// Template of the HTTPS transport endpoint bound to new DIDs, `[vid_placeholder]` is replaced by the DID
+    pub did_transport_format: String,
+    /// Scheme prefix of the TSP endpoint published for `tsp_address`: `tcp://`, `tls://` or `quic://`
     pub transport: String,
     /// Whether TSP messages should be printed
     pub verbose: bool,
+    /// Backend used to persist the wallet
+    pub wallet_type: WalletType,
     /// Wallet URL for secure storage
     pub wallet_url: String,
     /// Wallet password
     pub wallet_password: String,
+    /// Milliseconds without further wallet changes before they are saved
+    pub wallet_save_delay_ms: u64,
     /// Whether to use webvh DIDs
     pub use_webvh: bool,
     /// DID server address
     pub did_server: String,
     /// Type of DID to create
     pub did_type: DidType,
+    /// Scheme used to reach the DID server, `https` or `http`; the default resolver
+    /// (`DidServerResolver`) also uses it for DIDs published on the DID server
+    pub did_server_scheme: String,
+    /// PEM files with extra root certificates trusted for the DID server
+    pub did_server_root_certificates: Vec<String>,
+    /// PEM file with the client certificate presented to the DID server
+    pub did_server_client_certificate: Option<String>,
+    /// PEM file with the PKCS#8 private key of `did_server_client_certificate`
+    pub did_server_client_key: Option<String>,
+    /// Address (`host:port`) published as the `transport` TSP endpoint of new DIDs, defaults to `did_server`
+    pub tsp_address: Option<String>,
+    /// What to do when a peer's did:webvh log was rewritten or its keys changed unexpectedly
+    pub peer_history_policy: PeerHistoryPolicy,
+    /// JSON file pinning the keys of peers, defaults to `<wallet>.pins.json` next to the wallet
+    pub pin_store_path: Option<String>,
+    /// Peer DIDs clients may seal to and servers accept messages from
+    pub trust_policy: TrustPolicy,
+    /// Username our DID is published under; new DIDs use it, and a lost wallet looks it up to recover
+    pub did_username: Option<String>,
+    /// Our published DID, recovered when it is missing from the wallet
+    pub did: Option<String>,
+    /// Backup of the wallet keys, encrypted with `wallet_password`, used to recover our DID
+    pub key_backup_path: Option<String>,
+    /// Whether a new DID may be created and published when the wallet holds none for the alias
+    pub create_identity: bool,
+    /// Whether clients set up a TSP relationship with the server before their first MCP request
+    pub relationship_handshake: bool,
+    /// Whether servers refuse MCP requests from callers without a TSP relationship
+    pub require_relationship: bool,
+    /// Whether clients cancel their TSP relationship with the server when the session is deleted
+    /// or the client is closed
+    pub cancel_relationship_on_close: bool,
+    /// What happens to incoming messages that cannot be opened
+    pub open_failure_policy: OpenFailurePolicy,
 }
 
 impl Default for TmcpSettings {
     /*************  ✨ Windsurf Command ⭐  *************/
     /// Returns a default TmcpSettings configuration with the following settings:
     ///
-    /// * did_publish_url: https://did.teaspoon.world/add-vid
-    /// * did_publish_history_url: https://did.teaspoon.world/add-history/{did}
-    /// * did_web_format: did:web:did.teaspoon.world:endpoint:{name}
-    /// * did_webvh_format: did.teaspoon.world/endpoint/{name}
-    /// * transport: tmcp://
+    /// * did_publish_url: {scheme}://{server}/add-vid
+    /// * did_publish_history_url: {scheme}://{server}/add-history/{did}
+    /// * did_web_format: did:web:{server}:endpoint:{name}
+    /// * did_webvh_format: {server}/endpoint/{name}
+    /// * did_lookup_url: {scheme}://{server}/endpoint/{name}/did.jsonl
+    /// * did_transport_format: https://{server}/endpoint/[vid_placeholder]
+    /// * transport: tcp://
     /// * verbose: true
+    /// * wallet_type: Askar
     /// * wallet_url: sqlite://wallet.sqlite
     /// * wallet_password: unsecure
+    /// * wallet_save_delay_ms: 500
     /// * use_webvh: true
+    /// * did_server: did.teaspoon.world
+    /// * did_server_scheme: https
+    /// * peer_history_policy: Enforce
+    /// * create_identity: false
+    /// * relationship_handshake: false
+    /// * require_relationship: false
+    /// * cancel_relationship_on_close: false
+    /// * open_failure_policy: Drop
     /*******  e8082e7b-9eab-4f44-a17e-6a86ab880b84  *******/
     fn default() -> Self {
         Self {
-            did_publish_url: "https://did.teaspoon.world/add-vid".to_string(),
-            did_publish_history_url: "https://did.teaspoon.world/add-history/{did}".to_string(),
-            did_web_format: "did:web:did.teaspoon.world:endpoint:{name}".to_string(),
-            did_webvh_format: "did.teaspoon.world/endpoint/{name}".to_string(),
-            transport: "tmcp://".to_string(),
+            did_publish_url: "{scheme}://{server}/add-vid".to_string(),
+            did_publish_history_url: "{scheme}://{server}/add-history/{did}".to_string(),
+            did_web_format: "did:web:{server}:endpoint:{name}".to_string(),
+            did_webvh_format: "{server}/endpoint/{name}".to_string(),
+            did_lookup_url: "{scheme}://{server}/endpoint/{name}/did.jsonl".to_string(),
+            did_transport_format: "https://{server}/endpoint/[vid_placeholder]".to_string(),
+            transport: "tcp://".to_string(),
             verbose: true,
+            wallet_type: WalletType::Askar,
             wallet_url: "sqlite://wallet.sqlite".to_string(),
             wallet_password: "unsecure".to_string(),
+            wallet_save_delay_ms: 500,
             use_webvh: true,
             did_server: "did.teaspoon.world".to_string(),
             did_type: DidType::Webvh,
+            did_server_scheme: "https".to_string(),
+            did_server_root_certificates: Vec::new(),
+            did_server_client_certificate: None,
+            did_server_client_key: None,
+            tsp_address: None,
+            peer_history_policy: PeerHistoryPolicy::Enforce,
+            pin_store_path: None,
+            trust_policy: TrustPolicy::default(),
+            did_username: None,
+            did: None,
+            key_backup_path: None,
+            create_identity: false,
+            relationship_handshake: false,
+            require_relationship: false,
+            cancel_relationship_on_close: false,
+            open_failure_policy: OpenFailurePolicy::Drop,
+        }
+    }
+}
+
+impl TmcpSettings {
+    /// Checks the DID server templates: only known placeholders, the required ones present,
+    /// and valid URLs and DIDs once rendered.
+    #[allow(clippy::result_large_err)]
+    pub fn validate(&self) -> Result<(), TmcpError> {
+        if !matches!(self.did_server_scheme.as_str(), "https" | "http") {
+            return Err(invalid_setting("did_server_scheme", "must be https or http"));
+        }
+        check_template("did_publish_url", &self.did_publish_url, &["scheme", "server"], &[])?;
+        check_url("did_publish_url", &self.publish_url())?;
+        check_template(
+            "did_publish_history_url",
+            &self.did_publish_history_url,
+            &["scheme", "server", "did"],
+            &["did"],
+        )?;
+        check_url("did_publish_history_url", &self.publish_history_url("did:web:example.com"))?;
+        check_template("did_web_format", &self.did_web_format, &["server", "name"], &["name"])?;
+        if !self.did_web("name").starts_with("did:web:") {
+            return Err(invalid_setting("did_web_format", "must start with did:web:"));
+        }
+        check_template("did_webvh_format", &self.did_webvh_format, &["server", "name"], &["name"])?;
+        check_url("did_webvh_format", &format!("https://{}", self.did_webvh_path("name")))?;
+        check_template(
+            "did_lookup_url",
+            &self.did_lookup_url,
+            &["scheme", "server", "name"],
+            &["name"],
+        )?;
+        check_url("did_lookup_url", &self.lookup_url("name"))?;
+        check_template(
+            "did_transport_format",
+            &self.did_transport_format,
+            &["scheme", "server"],
+            &[],
+        )?;
+        check_url("did_transport_format", &self.transport_url())?;
+        if !matches!(self.transport.as_str(), "tcp://" | "tls://" | "quic://") {
+            return Err(invalid_setting("transport", "must be tcp://, tls:// or quic://"));
         }
+        check_url("tsp_address", &self.tsp_endpoint(self.tsp_address.as_deref().unwrap_or(&self.did_server)))?;
+        Ok(())
     }
+
+    /// URL new VIDs are published to
+    pub fn publish_url(&self) -> String {
+        render(&self.did_publish_url, &self.url_values(&[]))
+    }
+
+    /// URL the did:webvh history of `did` is published to
+    pub fn publish_history_url(&self, did: &str) -> String {
+        render(&self.did_publish_history_url, &self.url_values(&[("did", did)]))
+    }
+
+    /// The did:web published under `name`
+    pub fn did_web(&self, name: &str) -> String {
+        let server = self.did_server.replace(":", "%3A").replace("/", ":");
+        render(&self.did_web_format, &[("server", &server), ("name", name)])
+    }
+
+    /// Domain and path of the did:webvh published under `name`
+    pub fn did_webvh_path(&self, name: &str) -> String {
+        render(
+            &self.did_webvh_format,
+            &[("server", &self.did_server), ("name", name)],
+        )
+    }
+
+    /// URL of the DID log published under `name`
+    pub fn lookup_url(&self, name: &str) -> String {
+        render(&self.did_lookup_url, &self.url_values(&[("name", name)]))
+    }
+
+    /// TSP endpoint published for `address` (`host:port`), e.g. `tcp://127.0.0.1:1337`
+    pub fn tsp_endpoint(&self, address: &str) -> String {
+        format!("{}{}", self.transport, address)
+    }
+
+    /// HTTPS transport endpoint bound to new DIDs, still containing `[vid_placeholder]`
+    pub fn transport_url(&self) -> String {
+        render(&self.did_transport_format, &self.url_values(&[]))
+    }
+
+    /// Delay before wallet changes are saved, see `wallet_save_delay_ms`
+    pub fn wallet_save_delay(&self) -> Duration {
+        Duration::from_millis(self.wallet_save_delay_ms)
+    }
+
+    /// Placeholder values for the URL templates
+    fn url_values<'a>(&'a self, extra: &[(&'a str, &'a str)]) -> Vec<(&'a str, &'a str)> {
+        let mut values = vec![
+            ("scheme", self.did_server_scheme.as_str()),
+            ("server", self.did_server.as_str()),
+        ];
+        values.extend_from_slice(extra);
+        values
+    }
+
+    /// Builds an HTTP client trusting the configured root certificates and presenting the
+    /// configured client certificate.
+    #[allow(clippy::result_large_err)]
+    pub fn did_server_client(&self) -> Result<reqwest::Client, TmcpError> {
+        let mut builder = reqwest::Client::builder();
+        for path in &self.did_server_root_certificates {
+            let pem = std::fs::read(path)?;
+            builder = builder.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+        }
+        match (&self.did_server_client_certificate, &self.did_server_client_key) {
+            (Some(certificate), Some(key)) => {
+                let identity = reqwest::Identity::from_pkcs8_pem(
+                    &std::fs::read(certificate)?,
+                    &std::fs::read(key)?,
+                )?;
+                builder = builder.identity(identity);
+            }
+            (None, None) => {}
+            _ => {
+                return Err(invalid_setting(
+                    "did_server_client_certificate",
+                    "client certificate and key must be set together",
+                ));
+            }
+        }
+        Ok(builder.build()?)
+    }
+}
+
+/// Replaces every `{key}` in `template` with its value
+fn render(template: &str, values: &[(&str, &str)]) -> String {
+    values
+        .iter()
+        .fold(template.to_string(), |rendered, (key, value)| {
+            rendered.replace(&format!("{{{key}}}"), value)
+        })
+}
+
+/// The names of all `{...}` placeholders in `template`
+fn placeholders(template: &str) -> Vec<&str> {
+    template
+        .split('{')
+        .skip(1)
+        .filter_map(|part| part.split_once('}').map(|(name, _)| name))
+        .collect()
+}
+
+fn invalid_setting(setting: &str, reason: impl Into<String>) -> TmcpError {
+    TmcpError::InvalidSetting {
+        setting: setting.to_string(),
+        reason: reason.into(),
+    }
+}
+
+#[allow(clippy::result_large_err)]
+fn check_template(
+    setting: &str,
+    template: &str,
+    allowed: &[&str],
+    required: &[&str],
+) -> Result<(), TmcpError> {
+    let found = placeholders(template);
+    if let Some(unknown) = found.iter().find(|name| !allowed.contains(name)) {
+        return Err(invalid_setting(setting, format!("unknown placeholder {{{unknown}}}")));
+    }
+    if let Some(missing) = required.iter().find(|name| !found.contains(name)) {
+        return Err(invalid_setting(setting, format!("missing placeholder {{{missing}}}")));
+    }
+    Ok(())
+}
+
+#[allow(clippy::result_large_err)]
+fn check_url(setting: &str, url: &str) -> Result<(), TmcpError> {
+    Url::parse(url)
+        .map(|_| ())
+        .map_err(|e| invalid_setting(setting, format!("{url} is not a valid URL: {e}")))
 }
@@ -0,0 +1,62 @@
+//! Stdio transport for TMCP.
+//!
+//! Every newline-delimited JSON-RPC frame is sealed for the peer as URL-safe base64, which never
+//! contains a newline, so the framing of MCP's stdio transport is preserved.
+
+use futures::{SinkExt, StreamExt, future};
+use rmcp::service::{ServiceRole, TxJsonRpcMessage, RxJsonRpcMessage};
+use serde::{Serialize, de::DeserializeOwned};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{FramedRead, FramedWrite, LinesCodec};
+use tsp_sdk::AsyncSecureStore;
+
+use crate::{
+    errors::TmcpError,
+    settings::OpenFailurePolicy,
+    transport::{TmcpSink, TmcpStream, open_frame, seal_frame, until_failed},
+};
+
+/// Longest frame read, so that a peer never makes us buffer an endless line. A longer frame ends
+/// the transport.
+pub const MAX_FRAME_LENGTH: usize = 16 * 1024 * 1024;
+
+/// Builds a TSP protected transport over a reader/writer pair carrying newline-delimited frames.
+///
+/// Outgoing messages are sealed from `my_did` for `peer_did`; incoming frames are opened with
+/// `wallet` and only those sent by `peer_did` are passed on. Frames that cannot be opened are
+/// handled according to `on_failure`.
+pub fn transport<R, Rd, Wr>(
+    wallet: AsyncSecureStore,
+    my_did: String,
+    peer_did: String,
+    reader: Rd,
+    writer: Wr,
+    on_failure: OpenFailurePolicy,
+) -> (TmcpSink<R>, TmcpStream<R>)
+where
+    R: ServiceRole,
+    TxJsonRpcMessage<R>: Serialize,
+    RxJsonRpcMessage<R>: DeserializeOwned,
+    Rd: AsyncRead + Send + 'static,
+    Wr: AsyncWrite + Send + 'static,
+{
+    let seal_wallet = wallet.clone();
+    let open_peer = peer_did.clone();
+    let sink = FramedWrite::new(writer, LinesCodec::new_with_max_length(MAX_FRAME_LENGTH))
+        .sink_map_err(|e| TmcpError::TmcpError(e.to_string()))
+        .with(move |message: TxJsonRpcMessage<R>| {
+            future::ready(seal_frame(&message, &seal_wallet, &my_did, &peer_did))
+        });
+    let stream = FramedRead::new(reader, LinesCodec::new_with_max_length(MAX_FRAME_LENGTH)).filter_map(move |line| {
+        let message = match line {
+            Ok(line) if line.trim().is_empty() => None,
+            Ok(line) => open_frame(line, &wallet, &open_peer, on_failure),
+            Err(e) => {
+                log::error!("failed to read frame: {}", e);
+                None
+            }
+        };
+        future::ready(message)
+    });
+    (Box::pin(sink), until_failed(stream))
+}
@@ -0,0 +1,344 @@
+//! Wallet persistence.
+//!
+//! The wallet itself always lives in memory as an `AsyncSecureStore`; a [`WalletStorage`] reads
+//! it at startup and persists its exports. Which backend is used is selected with
+//! [`TmcpSettings::wallet_type`](crate::settings::TmcpSettings::wallet_type).
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use argon2::Argon2;
+use base64::{Engine as _, engine::general_purpose};
+use chacha20poly1305::{
+    AeadCore, KeyInit, XChaCha20Poly1305, XNonce,
+    aead::{Aead, OsRng, rand_core::RngCore},
+};
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tsp_sdk::{AskarSecureStorage, AsyncSecureStore, ExportVid, SecureStorage};
+
+use crate::{
+    errors::TmcpError,
+    settings::{TmcpSettings, WalletType},
+};
+
+/// Everything a wallet export holds: VIDs, aliases and did:webvh update keys
+pub type WalletExport = (
+    Vec<ExportVid>,
+    HashMap<String, String>,
+    HashMap<String, Vec<u8>>,
+);
+
+/// A place the wallet is read from and persisted to.
+pub trait WalletStorage: Send + Sync {
+    /// Reads the persisted wallet; an empty export if nothing was persisted yet
+    fn load(&self) -> BoxFuture<'_, Result<WalletExport, TmcpError>>;
+    /// Replaces the persisted wallet with `export`
+    fn save(&self, export: WalletExport) -> BoxFuture<'_, Result<(), TmcpError>>;
+}
+
+/// Opens the storage selected in `settings`, creating it if it does not exist yet
+pub async fn open(settings: &TmcpSettings) -> Result<Arc<dyn WalletStorage>, TmcpError> {
+    Ok(match settings.wallet_type {
+        WalletType::Askar => Arc::new(
+            AskarStorage::open(&settings.wallet_url, &settings.wallet_password).await?,
+        ),
+        WalletType::InMemory => Arc::new(InMemoryStorage::default()),
+        WalletType::EncryptedFile => Arc::new(EncryptedFileStorage::new(
+            settings.wallet_url.trim_start_matches("file://"),
+            &settings.wallet_password,
+        )),
+    })
+}
+
+/// A file kept next to the wallet selected in `settings`, `<wallet><suffix>`; none for in-memory wallets
+pub(crate) fn sidecar_path(settings: &TmcpSettings, suffix: &str) -> Option<String> {
+    let wallet_path = match settings.wallet_type {
+        WalletType::InMemory => return None,
+        WalletType::Askar => settings
+            .wallet_url
+            .strip_prefix("sqlite://")
+            .map(|path| path.split('?').next().unwrap_or(path))
+            .unwrap_or("tmcp"),
+        WalletType::EncryptedFile => settings.wallet_url.trim_start_matches("file://"),
+    };
+    Some(format!("{wallet_path}{suffix}"))
+}
+
+/// Writes a backup of the wallet selected in `settings` to `path`, encrypted with `wallet_password`.
+///
+/// Configure it as `key_backup_path` to recover the identity if the wallet is lost.
+pub async fn export_backup(settings: &TmcpSettings, path: impl Into<PathBuf>) -> Result<(), TmcpError> {
+    let export = open(settings).await?.load().await?;
+    EncryptedFileStorage::new(path, &settings.wallet_password)
+        .save(export)
+        .await
+}
+
+/// Askar storage, the default.
+///
+/// An opened sqlite wallet holds an exclusive lock on `<database>.lock` until it is dropped, so a
+/// second process can never load the wallet and overwrite the changes of the first one.
+pub struct AskarStorage {
+    store: AskarSecureStorage,
+    /// Released when the storage is dropped
+    _lock: Option<std::fs::File>,
+}
+
+impl AskarStorage {
+    /// Opens the Askar wallet at `url`, creating it if it does not exist yet.
+    ///
+    /// Fails with [`TmcpError::WalletStorage`] if another process has the sqlite wallet open.
+    pub async fn open(url: &str, password: &str) -> Result<Self, TmcpError> {
+        let lock = match url.strip_prefix("sqlite://") {
+            Some(path) => Some(lock(format!("{}.lock", path.split('?').next().unwrap_or(path)))?),
+            None => None,
+        };
+        let store = match AskarSecureStorage::open(url, password.as_bytes()).await {
+            Ok(store) => store,
+            Err(e) => {
+                log::warn!("Warning:unable to open storage: {:?}. Creating a new one", e);
+                AskarSecureStorage::new(url, password.as_bytes()).await?
+            }
+        };
+        Ok(Self { store, _lock: lock })
+    }
+}
+
+/// Takes the cross-process lock at `lock_path`; it is released when the returned file is dropped
+#[allow(clippy::result_large_err)]
+fn lock(lock_path: String) -> Result<std::fs::File, TmcpError> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(std::fs::TryLockError::WouldBlock) => Err(TmcpError::WalletStorage(format!(
+            "wallet is in use by another process ({lock_path} is locked)"
+        ))),
+        Err(std::fs::TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+impl WalletStorage for AskarStorage {
+    fn load(&self) -> BoxFuture<'_, Result<WalletExport, TmcpError>> {
+        Box::pin(async move { Ok(self.store.read().await?) })
+    }
+
+    fn save(&self, export: WalletExport) -> BoxFuture<'_, Result<(), TmcpError>> {
+        Box::pin(async move { Ok(self.store.persist(export).await?) })
+    }
+}
+
+/// Saves a wallet to its storage after it changed.
+///
+/// Changes are coalesced: the wallet is saved once no further change was reported for the
+/// configured delay, and [`Persister::flush`] saves right away. Pending changes are also saved
+/// when the last clone is dropped, but on a background task that does not survive the shutdown of
+/// the runtime: call [`Persister::flush`] before shutting down.
+#[derive(Clone)]
+pub(crate) struct Persister {
+    storage: Arc<dyn WalletStorage>,
+    wallet: AsyncSecureStore,
+    changes: mpsc::UnboundedSender<()>,
+    saving: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl Persister {
+    /// Starts saving `wallet` to `storage` in the background
+    pub(crate) fn new(storage: Arc<dyn WalletStorage>, wallet: AsyncSecureStore, delay: Duration) -> Self {
+        let (changes, mut changed) = mpsc::unbounded_channel();
+        let persister = Self {
+            storage,
+            wallet,
+            changes,
+            saving: Arc::default(),
+        };
+        let (storage, wallet, saving) = (
+            persister.storage.clone(),
+            persister.wallet.clone(),
+            persister.saving.clone(),
+        );
+        tokio::spawn(async move {
+            while changed.recv().await.is_some() {
+                // Wait until no change was reported for `delay`, or all senders are gone
+                while let Ok(Some(())) = tokio::time::timeout(delay, changed.recv()).await {}
+                if let Err(e) = save(storage.as_ref(), &wallet, &saving).await {
+                    log::error!("unable to persist wallet: {}", e);
+                }
+            }
+        });
+        persister
+    }
+
+    /// Reports a change to the wallet, to be saved after the delay
+    pub(crate) fn changed(&self) {
+        let _ = self.changes.send(());
+    }
+
+    /// Saves the wallet now
+    pub(crate) async fn flush(&self) -> Result<(), TmcpError> {
+        save(self.storage.as_ref(), &self.wallet, &self.saving).await
+    }
+}
+
+async fn save(
+    storage: &dyn WalletStorage,
+    wallet: &AsyncSecureStore,
+    saving: &tokio::sync::Mutex<()>,
+) -> Result<(), TmcpError> {
+    // Export under the lock, so an older export never overwrites a newer one
+    let _saving = saving.lock().await;
+    storage.save(wallet.export()?).await
+}
+
+/// The serialized form of a [`WalletExport`]
+#[derive(Default, Serialize, Deserialize)]
+struct WalletContents {
+    vids: Vec<ExportVid>,
+    aliases: HashMap<String, String>,
+    keys: HashMap<String, Vec<u8>>,
+}
+
+impl From<WalletExport> for WalletContents {
+    fn from((vids, aliases, keys): WalletExport) -> Self {
+        Self {
+            vids,
+            aliases,
+            keys,
+        }
+    }
+}
+
+impl From<WalletContents> for WalletExport {
+    fn from(contents: WalletContents) -> Self {
+        (contents.vids, contents.aliases, contents.keys)
+    }
+}
+
+/// Ephemeral storage for tests and short-lived agents; nothing survives the process.
+#[derive(Default)]
+pub struct InMemoryStorage {
+    contents: Mutex<Vec<u8>>,
+}
+
+impl WalletStorage for InMemoryStorage {
+    fn load(&self) -> BoxFuture<'_, Result<WalletExport, TmcpError>> {
+        let contents = self.contents.lock().expect("storage lock poisoned").clone();
+        Box::pin(async move {
+            if contents.is_empty() {
+                return Ok(WalletContents::default().into());
+            }
+            let contents: WalletContents = serde_json::from_slice(&contents)?;
+            Ok(contents.into())
+        })
+    }
+
+    fn save(&self, export: WalletExport) -> BoxFuture<'_, Result<(), TmcpError>> {
+        Box::pin(async move {
+            let contents = serde_json::to_vec(&WalletContents::from(export))?;
+            *self.contents.lock().expect("storage lock poisoned") = contents;
+            Ok(())
+        })
+    }
+}
+
+/// On-disk format of [`EncryptedFileStorage`]
+#[derive(Serialize, Deserialize)]
+struct EncryptedFile {
+    version: u32,
+    /// Argon2id salt, URL-safe base64
+    salt: String,
+    /// XChaCha20-Poly1305 nonce, URL-safe base64
+    nonce: String,
+    /// Encrypted [`WalletContents`], URL-safe base64
+    ciphertext: String,
+}
+
+/// A single JSON file encrypted with a key derived from the wallet password, e.g. for container images.
+pub struct EncryptedFileStorage {
+    path: PathBuf,
+    password: String,
+}
+
+impl EncryptedFileStorage {
+    pub fn new(path: impl Into<PathBuf>, password: &str) -> Self {
+        Self {
+            path: path.into(),
+            password: password.to_string(),
+        }
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn cipher(&self, salt: &[u8]) -> Result<XChaCha20Poly1305, TmcpError> {
+        let mut key = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(self.password.as_bytes(), salt, &mut key)
+            .map_err(|e| TmcpError::WalletStorage(e.to_string()))?;
+        Ok(XChaCha20Poly1305::new(&key.into()))
+    }
+}
+
+impl WalletStorage for EncryptedFileStorage {
+    fn load(&self) -> BoxFuture<'_, Result<WalletExport, TmcpError>> {
+        Box::pin(async move {
+            let file = match tokio::fs::read(&self.path).await {
+                Ok(file) => file,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    return Ok(WalletContents::default().into());
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let file: EncryptedFile = serde_json::from_slice(&file)?;
+            if file.version != 1 {
+                return Err(TmcpError::WalletStorage(format!(
+                    "unsupported wallet file version {}",
+                    file.version
+                )));
+            }
+            let salt = general_purpose::URL_SAFE.decode(&file.salt)?;
+            let nonce: [u8; 24] = general_purpose::URL_SAFE
+                .decode(&file.nonce)?
+                .try_into()
+                .map_err(|_| TmcpError::WalletStorage("invalid nonce in wallet file".into()))?;
+            let ciphertext = general_purpose::URL_SAFE.decode(&file.ciphertext)?;
+            let plaintext = self
+                .cipher(&salt)?
+                .decrypt(&XNonce::from(nonce), ciphertext.as_slice())
+                .map_err(|_| TmcpError::WalletStorage("wrong password or corrupted wallet file".into()))?;
+            let contents: WalletContents = serde_json::from_slice(&plaintext)?;
+            Ok(contents.into())
+        })
+    }
+
+    fn save(&self, export: WalletExport) -> BoxFuture<'_, Result<(), TmcpError>> {
+        Box::pin(async move {
+            let plaintext = serde_json::to_vec(&WalletContents::from(export))?;
+            let mut salt = [0u8; 16];
+            OsRng.fill_bytes(&mut salt);
+            let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+            let ciphertext = self
+                .cipher(&salt)?
+                .encrypt(&nonce, plaintext.as_slice())
+                .map_err(|e| TmcpError::WalletStorage(e.to_string()))?;
+            let file = EncryptedFile {
+                version: 1,
+                salt: general_purpose::URL_SAFE.encode(salt),
+                nonce: general_purpose::URL_SAFE.encode(nonce),
+                ciphertext: general_purpose::URL_SAFE.encode(ciphertext),
+            };
+            // Write next to the wallet and rename, so a crash never leaves a truncated wallet behind
+            let tmp_path = self.path.with_extension("tmp");
+            tokio::fs::write(&tmp_path, serde_json::to_vec(&file)?).await?;
+            tokio::fs::rename(&tmp_path, &self.path).await?;
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,66 @@
+//! Native TSP transport for TMCP.
+//!
+//! Instead of tunnelling TSP through HTTP, JSON-RPC messages are sealed and sent straight to the
+//! transport endpoint published in the peer's DID (e.g. the `tcp://` endpoints `create` provisions),
+//! using tsp_sdk's transport layer. Incoming messages are received on our own DID's endpoint.
+
+use futures::{StreamExt, future, sink};
+use rmcp::service::{RxJsonRpcMessage, ServiceRole, TxJsonRpcMessage};
+use serde::{Serialize, de::DeserializeOwned};
+use tsp_sdk::{AsyncSecureStore, ReceivedTspMessage};
+
+use crate::{
+    errors::TmcpError,
+    settings::OpenFailurePolicy,
+    transport::{TmcpSink, TmcpStream, open_failed, until_failed},
+};
+
+/// Builds a transport exchanging messages between `my_did` and `peer_did` over their TSP endpoints.
+///
+/// Starts listening on the transport endpoint of `my_did`, so this has to run on the machine
+/// that endpoint points to. Messages that cannot be opened are handled according to `on_failure`.
+pub async fn transport<R>(
+    wallet: AsyncSecureStore,
+    my_did: String,
+    peer_did: String,
+    on_failure: OpenFailurePolicy,
+) -> Result<(TmcpSink<R>, TmcpStream<R>), TmcpError>
+where
+    R: ServiceRole,
+    TxJsonRpcMessage<R>: Serialize,
+    RxJsonRpcMessage<R>: DeserializeOwned,
+{
+    let messages = wallet.receive(&my_did).await?;
+    let sink = sink::unfold(
+        (wallet, my_did, peer_did.clone()),
+        |(wallet, my_did, peer_did), message: TxJsonRpcMessage<R>| async move {
+            let json = serde_json::to_vec(&message)?;
+            wallet.send(&my_did, &peer_did, None, &json).await?;
+            Ok::<_, TmcpError>((wallet, my_did, peer_did))
+        },
+    );
+    let stream = messages.filter_map(move |message| {
+        let message = match message {
+            Ok(ReceivedTspMessage::GenericMessage {
+                sender, message, ..
+            }) if sender == peer_did => match serde_json::from_slice(&message) {
+                Ok(message) => Some(Ok(message)),
+                Err(e) => {
+                    log::error!("failed to parse message: {}", e);
+                    None
+                }
+            },
+            Ok(ReceivedTspMessage::GenericMessage { sender, .. }) => {
+                log::error!("dropping message from unexpected sender {}", sender);
+                None
+            }
+            Ok(_) => {
+                log::debug!("ignoring TSP control message");
+                None
+            }
+            Err(e) => open_failed(e.into(), on_failure),
+        };
+        future::ready(message)
+    });
+    Ok((Box::pin(sink), until_failed(stream)))
+}
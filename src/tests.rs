@@ -1,13 +1,95 @@
-use tsp_sdk::{AskarSecureStorage, SecureStorage, SecureStore};
+use std::{sync::Arc, time::Duration};
 
-use crate::{TmcpClient, settings};
+use reqwest::Url;
+use tsp_sdk::{AskarSecureStorage, AsyncSecureStore, OwnedVid, SecureStorage, SecureStore, VerifiedVid};
 
+use crate::{
+    InMemoryResolver, ServerIdentity, TmcpClient,
+    metadata::MetadataStore,
+    pins::{Pin, PinStore},
+    settings,
+    storage::{EncryptedFileStorage, InMemoryStorage, Persister, WalletStorage},
+    transport,
+    tsp_messages::{self, TspMessage},
+    verify, wallet, webvh,
+};
+
+/// A did:peer with a placeholder endpoint
+fn peer_vid() -> OwnedVid {
+    OwnedVid::new_did_peer(Url::parse("tcp://127.0.0.1:1337").unwrap())
+}
+
+/// A wallet holding `vid` as the `pigeon` identity
+fn pigeon_wallet(vid: &OwnedVid) -> AsyncSecureStore {
+    let wallet = AsyncSecureStore::new();
+    wallet.add_private_vid(vid.clone(), None).unwrap();
+    wallet.set_alias("pigeon".to_string(), vid.identifier().to_string()).unwrap();
+    wallet
+}
+
+/// Wallets for `alice` and `bob` that hold their own identity and have verified the other one
+fn verified_pair(alice: &OwnedVid, bob: &OwnedVid) -> (AsyncSecureStore, AsyncSecureStore) {
+    let alice_wallet = AsyncSecureStore::new();
+    alice_wallet.add_private_vid(alice.clone(), None).unwrap();
+    alice_wallet.add_verified_vid(bob.vid().clone(), None).unwrap();
+    let bob_wallet = AsyncSecureStore::new();
+    bob_wallet.add_private_vid(bob.clone(), None).unwrap();
+    bob_wallet.add_verified_vid(alice.vid().clone(), None).unwrap();
+    (alice_wallet, bob_wallet)
+}
+
+/// A server acting as `vid`, verifying its callers with `resolver`
+fn server_identity(vid: &OwnedVid, resolver: Arc<InMemoryResolver>) -> ServerIdentity {
+    let wallet = pigeon_wallet(vid);
+    let persister = Persister::new(Arc::new(InMemoryStorage::default()), wallet.clone(), Duration::from_millis(50));
+    ServerIdentity::from_parts(vid.identifier().to_string(), wallet, resolver, persister)
+}
+
+/// A JSON-RPC message of either side
+fn json_rpc<T: serde::de::DeserializeOwned>(json: &str) -> T {
+    serde_json::from_str(json).unwrap()
+}
+
+/// Settings for identities that are not published as did:webvh
+fn peer_settings() -> settings::TmcpSettings {
+    settings::TmcpSettings {
+        use_webvh: false,
+        ..Default::default()
+    }
+}
+
+/// A new did:webvh hosted at `location` (`example.com:endpoint:tmcp`) and the signed first entry of
+/// its log, with `update_key` as the only update key
+fn first_log_entry(location: &str, update_key: &ed25519_dalek::SigningKey) -> (String, serde_json::Value) {
+    let preliminary = serde_json::json!({
+        "versionId": "{SCID}",
+        "versionTime": "2025-01-01T00:00:00Z",
+        "parameters": {
+            "method": "did:webvh:1.0",
+            "scid": "{SCID}",
+            "updateKeys": [webvh::multikey(update_key)],
+        },
+        "state": { "id": format!("did:webvh:{{SCID}}:{location}") },
+    });
+    let scid = webvh::multihash(&serde_jcs::to_vec(&preliminary).unwrap());
+    let mut first: serde_json::Value =
+        serde_json::from_str(&preliminary.to_string().replace("{SCID}", &scid)).unwrap();
+    let entry_hash = webvh::multihash(&serde_jcs::to_vec(&first).unwrap());
+    first["versionId"] = format!("1-{entry_hash}").into();
+    let did = format!("did:webvh:{scid}:{location}");
+    (did, webvh::sign(first, update_key).unwrap())
+}
+
+/// Needs did.teaspoon.world; `test_tmcp_client_with_local_did_server` covers the same offline
 #[tokio::test]
+#[ignore = "needs network access to did.teaspoon.world"]
 async fn test_tmcp_client() {
     let other_did = "did:webvh:QmTzEvHuLRS1vrkThAxSiovo2CsCz5T1oNBdyRDLEwh6yi:did.teaspoon.world:endpoint:tmcp-83a1d6ab-3130-444b-90a5-9687b45e25fb";
-    let client = TmcpClient::new("pigeon", other_did, settings::TmcpSettings::default())
-        .await
-        .unwrap();
+    let settings = settings::TmcpSettings {
+        create_identity: true,
+        ..Default::default()
+    };
+    let client = TmcpClient::new("pigeon", other_did, settings).await.unwrap();
     {
         let vault = AskarSecureStorage::open("sqlite://wallet.sqlite", b"unsecure")
             .await
@@ -26,3 +108,1230 @@ async fn test_tmcp_client() {
         vault.destroy().await.unwrap();
     }
 }
+
+#[test]
+fn test_settings_templates() {
+    let settings = settings::TmcpSettings {
+        did_server: "localhost:8080".to_string(),
+        ..Default::default()
+    };
+    settings.validate().unwrap();
+    assert_eq!(settings.publish_url(), "https://localhost:8080/add-vid");
+    assert_eq!(settings.did_web("alice"), "did:web:localhost%3A8080:endpoint:alice");
+    assert_eq!(
+        settings.lookup_url("alice"),
+        "https://localhost:8080/endpoint/alice/did.jsonl"
+    );
+
+    let plain_http = settings::TmcpSettings {
+        did_server_scheme: "http".to_string(),
+        ..settings.clone()
+    };
+    assert_eq!(plain_http.publish_url(), "http://localhost:8080/add-vid");
+    let unknown_scheme = settings::TmcpSettings {
+        did_server_scheme: "ftp".to_string(),
+        ..settings.clone()
+    };
+    assert!(unknown_scheme.validate().is_err());
+
+    let missing_did = settings::TmcpSettings {
+        did_publish_history_url: "https://{server}/add-history".to_string(),
+        ..Default::default()
+    };
+    assert!(missing_did.validate().is_err());
+
+    let unknown_transport = settings::TmcpSettings {
+        transport: "tmcp://".to_string(),
+        ..Default::default()
+    };
+    assert!(unknown_transport.validate().is_err());
+    let tls = settings::TmcpSettings {
+        transport: "tls://".to_string(),
+        tsp_address: Some("127.0.0.1:1337".to_string()),
+        ..Default::default()
+    };
+    tls.validate().unwrap();
+    assert_eq!(tls.tsp_endpoint("127.0.0.1:1337"), "tls://127.0.0.1:1337");
+
+    let unknown_placeholder = settings::TmcpSettings {
+        did_web_format: "did:web:{host}:{name}".to_string(),
+        ..Default::default()
+    };
+    assert!(unknown_placeholder.validate().is_err());
+}
+
+#[test]
+fn test_open_control_message() {
+    let alice = peer_vid();
+    let bob = peer_vid();
+    let alice_wallet = AsyncSecureStore::new();
+    alice_wallet.add_private_vid(alice.clone(), None).unwrap();
+    alice_wallet.add_verified_vid(bob.vid().clone(), None).unwrap();
+    let bob_wallet = AsyncSecureStore::new();
+    bob_wallet.add_private_vid(bob.clone(), None).unwrap();
+    bob_wallet.add_verified_vid(alice.vid().clone(), None).unwrap();
+
+    let (_url, mut request) = alice_wallet
+        .make_relationship_request(alice.identifier(), bob.identifier(), None)
+        .unwrap();
+    let opened = tsp_messages::open_bytes(&mut request, &bob_wallet).unwrap();
+    assert!(matches!(opened, TspMessage::RelationshipRequest { .. }));
+    assert_eq!(opened.sender(), Some(alice.identifier()));
+    assert!(opened.into_generic().is_err());
+
+    let mut message =
+        tsp_messages::seal_bytes(b"hello", &alice_wallet, alice.identifier(), bob.identifier()).unwrap();
+    let opened = tsp_messages::open_bytes(&mut message, &bob_wallet).unwrap();
+    assert_eq!(opened.into_generic().unwrap().payload, "hello");
+}
+
+#[test]
+fn test_seal_failure_names_peer() {
+    let alice = peer_vid();
+    let wallet = AsyncSecureStore::new();
+    wallet.add_private_vid(alice.clone(), None).unwrap();
+
+    let unknown = "did:web:unknown.example";
+    match tsp_messages::seal_message("hello".to_string(), &wallet, alice.identifier(), unknown) {
+        Err(crate::errors::TmcpError::SealFailed { peer_did, .. }) => assert_eq!(peer_did, unknown),
+        other => panic!("expected SealFailed, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_open_failure_policy() {
+    use settings::OpenFailurePolicy;
+    use transport::open_frame;
+
+    let wallet = AsyncSecureStore::new();
+    let plaintext = r#"{"jsonrpc":"2.0","method":"notifications/initialized"}"#.to_string();
+    let peer = "did:web:peer.example";
+
+    let dropped = open_frame::<rmcp::RoleClient>(plaintext.clone(), &wallet, peer, OpenFailurePolicy::Drop);
+    assert!(dropped.is_none());
+    let dropped = open_frame::<rmcp::RoleClient>(plaintext.clone(), &wallet, peer, OpenFailurePolicy::JsonRpcError);
+    assert!(dropped.is_none());
+    let failed = open_frame::<rmcp::RoleClient>(plaintext, &wallet, peer, OpenFailurePolicy::FailStream);
+    assert!(matches!(failed, Some(Err(_))));
+}
+
+#[tokio::test]
+async fn test_open_failure_policy_on_sse_stream() {
+    use futures::StreamExt;
+    use rmcp::{model::RequestId, transport::streamable_http_client::SseError};
+    use settings::OpenFailurePolicy;
+    use sse_stream::Sse;
+
+    let me = peer_vid();
+    let peer = peer_vid();
+    let resolver = Arc::new(InMemoryResolver::new());
+    resolver.insert(me.vid().clone(), None);
+    resolver.insert(peer.vid().clone(), None);
+    let peer_wallet = AsyncSecureStore::new();
+    peer_wallet.add_private_vid(peer.clone(), None).unwrap();
+    peer_wallet.add_verified_vid(me.vid().clone(), None).unwrap();
+
+    let event = |data: String| Sse {
+        data: Some(data),
+        ..Default::default()
+    };
+    let sealed = |payload: &str| {
+        let message = payload.to_string();
+        event(tsp_messages::seal_message(message, &peer_wallet, peer.identifier(), me.identifier()).unwrap())
+    };
+    let events = || {
+        futures::stream::iter([
+            sealed("first"),
+            event(r#"{"jsonrpc":"2.0","id":1,"result":{}}"#.to_string()),
+            sealed("last"),
+        ])
+        .map(Ok)
+    };
+    let client = |policy: OpenFailurePolicy| {
+        TmcpClient::builder("pigeon", peer.identifier())
+            .settings(settings::TmcpSettings {
+                open_failure_policy: policy,
+                ..peer_settings()
+            })
+            .resolver(resolver.clone())
+            .wallet(pigeon_wallet(&me))
+            .pin_store(PinStore::in_memory())
+            .build()
+    };
+    let payloads = |events: Vec<Result<Sse, SseError>>| {
+        events
+            .into_iter()
+            .map(|event| event.ok().and_then(|event| event.data))
+            .collect::<Vec<_>>()
+    };
+
+    // The injected event is dropped
+    let dropped = client(OpenFailurePolicy::Drop).await.unwrap();
+    let opened = dropped.open_sse_stream(events(), Some(RequestId::Number(1))).collect().await;
+    assert_eq!(payloads(opened), [Some("first".to_string()), Some("last".to_string())]);
+
+    // The stream fails at the injected event and ends there
+    let failing = client(OpenFailurePolicy::FailStream).await.unwrap();
+    let opened = failing.open_sse_stream(events(), Some(RequestId::Number(1))).collect().await;
+    assert_eq!(payloads(opened), [Some("first".to_string()), None]);
+
+    // The request in flight is answered with one error instead of the injected result, which ends
+    // the stream
+    let answering = client(OpenFailurePolicy::JsonRpcError).await.unwrap();
+    let twice = futures::stream::iter([event("injected".to_string()), event("again".to_string()), sealed("last")]);
+    let opened = payloads(answering.open_sse_stream(twice.map(Ok), Some(RequestId::Number(1))).collect().await);
+    assert_eq!(opened.len(), 1);
+    let error: serde_json::Value = serde_json::from_str(opened[0].as_deref().unwrap()).unwrap();
+    assert_eq!(error["id"], 1);
+    assert!(error["error"]["message"].as_str().unwrap().contains("unable to open TSP message"));
+    let opened = payloads(answering.open_sse_stream(events(), Some(RequestId::Number(1))).collect().await);
+    assert_eq!(opened.len(), 2);
+    assert_eq!(opened[0].as_deref(), Some("first"));
+    let opened = answering.open_sse_stream(events(), None).collect().await;
+    assert_eq!(payloads(opened), [Some("first".to_string()), Some("last".to_string())]);
+
+    // Messages from anyone but the peer are not passed on either
+    let stranger = peer_vid();
+    let (stranger_wallet, _) = verified_pair(&stranger, &me);
+    dropped.wallet.add_verified_vid(stranger.vid().clone(), None).unwrap();
+    let forged = tsp_messages::seal_message("forged".to_string(), &stranger_wallet, stranger.identifier(), me.identifier());
+    let events = futures::stream::iter([event(forged.unwrap()), sealed("last")]).map(Ok);
+    let opened = dropped.open_sse_stream(events, Some(RequestId::Number(1))).collect().await;
+    assert_eq!(payloads(opened), [Some("last".to_string())]);
+}
+
+#[tokio::test]
+async fn test_json_response_from_peer() {
+    use rmcp::{model::RequestId, transport::streamable_http_client::StreamableHttpPostResponse};
+    use settings::OpenFailurePolicy;
+
+    let me = peer_vid();
+    let peer = peer_vid();
+    let stranger = peer_vid();
+    let resolver = Arc::new(InMemoryResolver::new());
+    resolver.insert(peer.vid().clone(), None);
+    let (peer_wallet, _) = verified_pair(&peer, &me);
+    let (stranger_wallet, _) = verified_pair(&stranger, &me);
+    let build = |policy: OpenFailurePolicy| {
+        TmcpClient::builder("pigeon", peer.identifier())
+            .settings(settings::TmcpSettings {
+                open_failure_policy: policy,
+                ..peer_settings()
+            })
+            .resolver(resolver.clone())
+            .wallet(pigeon_wallet(&me))
+            .pin_store(PinStore::in_memory())
+            .build()
+    };
+    let response = |body: String| {
+        reqwest::Response::from(
+            http::Response::builder()
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(body)
+                .unwrap(),
+        )
+    };
+    let result = r#"{"jsonrpc":"2.0","id":1,"result":{}}"#.to_string();
+    let request_id = || Some(RequestId::Number(1));
+
+    let client = build(OpenFailurePolicy::Drop).await.unwrap();
+    let sealed = tsp_messages::seal_message(result.clone(), &peer_wallet, peer.identifier(), me.identifier());
+    assert!(matches!(
+        client.handle_response(response(sealed.unwrap()), request_id()).await,
+        Ok(StreamableHttpPostResponse::Json(..))
+    ));
+
+    // A response sealed by anyone but the peer fails the request
+    client.wallet.add_verified_vid(stranger.vid().clone(), None).unwrap();
+    let forged = tsp_messages::seal_message(result, &stranger_wallet, stranger.identifier(), me.identifier());
+    assert!(client.handle_response(response(forged.unwrap()), request_id()).await.is_err());
+
+    // So does a control message in place of the response, unless the request is answered with an error
+    let control = || tsp_messages::relationship_request(&peer_wallet, peer.identifier(), me.identifier()).unwrap();
+    assert!(client.handle_response(response(control()), request_id()).await.is_err());
+    assert!(matches!(
+        client.handle_response(response(control()), None).await,
+        Ok(StreamableHttpPostResponse::Accepted)
+    ));
+    let answering = build(OpenFailurePolicy::JsonRpcError).await.unwrap();
+    let Ok(StreamableHttpPostResponse::Json(error, _)) =
+        answering.handle_response(response(control()), request_id()).await
+    else {
+        panic!("expected a JSON-RPC error");
+    };
+    let error = serde_json::to_value(error).unwrap();
+    assert_eq!(error["id"], 1);
+    assert!(error["error"]["message"].as_str().unwrap().contains("relationship request"));
+}
+
+#[test]
+fn test_relationship_handshake() {
+    let alice = peer_vid();
+    let bob = peer_vid();
+    let alice_wallet = AsyncSecureStore::new();
+    alice_wallet.add_private_vid(alice.clone(), None).unwrap();
+    alice_wallet.add_verified_vid(bob.vid().clone(), None).unwrap();
+    let bob_wallet = AsyncSecureStore::new();
+    bob_wallet.add_private_vid(bob.clone(), None).unwrap();
+    bob_wallet.add_verified_vid(alice.vid().clone(), None).unwrap();
+    assert!(!tsp_messages::is_related(&alice_wallet, alice.identifier(), bob.identifier()).unwrap());
+
+    let request = tsp_messages::relationship_request(&alice_wallet, alice.identifier(), bob.identifier()).unwrap();
+    let TspMessage::RelationshipRequest { thread_id, .. } = tsp_messages::open_message(request, &bob_wallet).unwrap()
+    else {
+        panic!("expected a relationship request");
+    };
+    let accept =
+        tsp_messages::relationship_accept(&bob_wallet, bob.identifier(), alice.identifier(), thread_id).unwrap();
+    assert!(matches!(
+        tsp_messages::open_message(accept, &alice_wallet).unwrap(),
+        TspMessage::RelationshipAccept { .. }
+    ));
+
+    assert!(tsp_messages::is_related(&alice_wallet, alice.identifier(), bob.identifier()).unwrap());
+    assert!(tsp_messages::is_related(&bob_wallet, bob.identifier(), alice.identifier()).unwrap());
+
+    let cancel = tsp_messages::relationship_cancel(&alice_wallet, alice.identifier(), bob.identifier()).unwrap();
+    assert!(!tsp_messages::is_related(&alice_wallet, alice.identifier(), bob.identifier()).unwrap());
+    assert!(matches!(
+        tsp_messages::open_message(cancel, &bob_wallet).unwrap(),
+        TspMessage::RelationshipCancel { .. }
+    ));
+    assert!(!tsp_messages::is_related(&bob_wallet, bob.identifier(), alice.identifier()).unwrap());
+}
+
+#[tokio::test]
+async fn test_stdio_transport() {
+    use futures::{SinkExt, StreamExt};
+    use rmcp::{RoleClient, RoleServer};
+    use settings::OpenFailurePolicy;
+    use tokio::io::AsyncWriteExt;
+
+    let alice = peer_vid();
+    let bob = peer_vid();
+    let (alice_wallet, bob_wallet) = verified_pair(&alice, &bob);
+    let (alice_did, bob_did) = (alice.identifier().to_string(), bob.identifier().to_string());
+    let request = r#"{"jsonrpc":"2.0","id":1,"method":"ping"}"#;
+    let response = r#"{"jsonrpc":"2.0","id":1,"result":{}}"#;
+
+    let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+    let (client_read, client_write) = tokio::io::split(client_io);
+    let (server_read, server_write) = tokio::io::split(server_io);
+    let (mut client_sink, mut client_stream) = crate::stdio::transport::<RoleClient, _, _>(
+        alice_wallet.clone(),
+        alice_did.clone(),
+        bob_did.clone(),
+        client_read,
+        client_write,
+        OpenFailurePolicy::Drop,
+    );
+    let (mut server_sink, mut server_stream) = crate::stdio::transport::<RoleServer, _, _>(
+        bob_wallet.clone(),
+        bob_did.clone(),
+        alice_did.clone(),
+        server_read,
+        server_write,
+        OpenFailurePolicy::Drop,
+    );
+    client_sink.send(json_rpc(request)).await.unwrap();
+    let received = server_stream.next().await.unwrap();
+    assert_eq!(serde_json::to_value(received).unwrap(), json_rpc::<serde_json::Value>(request));
+    server_sink.send(json_rpc(response)).await.unwrap();
+    let received = client_stream.next().await.unwrap();
+    assert_eq!(serde_json::to_value(received).unwrap(), json_rpc::<serde_json::Value>(response));
+
+    // Plaintext frames are dropped, a frame over the maximum length ends the transport
+    let (mut raw, server_io) = tokio::io::duplex(64 * 1024);
+    let (server_read, server_write) = tokio::io::split(server_io);
+    let (_server_sink, mut server_stream) = crate::stdio::transport::<RoleServer, _, _>(
+        bob_wallet,
+        bob_did.clone(),
+        alice_did.clone(),
+        server_read,
+        server_write,
+        OpenFailurePolicy::Drop,
+    );
+    let sealed = transport::seal_frame::<RoleClient>(&json_rpc(request), &alice_wallet, &alice_did, &bob_did).unwrap();
+    tokio::spawn(async move {
+        raw.write_all(format!("{request}\n{sealed}\n").as_bytes()).await?;
+        raw.write_all(&vec![b'a'; crate::stdio::MAX_FRAME_LENGTH + 1]).await?;
+        raw.write_all(format!("\n{sealed}\n").as_bytes()).await
+    });
+    let received = server_stream.next().await.unwrap();
+    assert_eq!(serde_json::to_value(received).unwrap(), json_rpc::<serde_json::Value>(request));
+    assert!(server_stream.next().await.is_none());
+}
+
+#[tokio::test]
+async fn test_websocket_transport() {
+    use futures::{SinkExt, StreamExt};
+    use rmcp::RoleClient;
+    use settings::OpenFailurePolicy;
+
+    let alice = peer_vid();
+    let bob = peer_vid();
+    let (alice_wallet, _) = verified_pair(&alice, &bob);
+    let resolver = Arc::new(InMemoryResolver::new());
+    resolver.insert(alice.vid().clone(), None);
+    let server = crate::TmcpWebSocketServer::from_identity(server_identity(&bob, resolver), &peer_settings());
+    let request = r#"{"jsonrpc":"2.0","id":1,"method":"ping"}"#;
+    let response = r#"{"jsonrpc":"2.0","id":1,"result":{}}"#;
+
+    let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+    let accept = tokio::spawn({
+        let server = server.clone();
+        async move { server.accept(server_io).await }
+    });
+    let url = format!("ws://localhost/mcp?did={}", alice.identifier());
+    let (socket, _) = tokio_tungstenite::client_async(url, client_io).await.unwrap();
+    let (mut client_sink, mut client_stream) = crate::websocket::transport::<RoleClient, _>(
+        socket,
+        alice_wallet,
+        alice.identifier().to_string(),
+        bob.identifier().to_string(),
+        OpenFailurePolicy::Drop,
+    );
+    let (sender, mut server_sink, mut server_stream) = accept.await.unwrap().unwrap();
+    assert_eq!(sender.as_str(), alice.identifier());
+
+    client_sink.send(json_rpc(request)).await.unwrap();
+    let received = server_stream.next().await.unwrap();
+    assert_eq!(serde_json::to_value(received).unwrap(), json_rpc::<serde_json::Value>(request));
+    server_sink.send(json_rpc(response)).await.unwrap();
+    let received = client_stream.next().await.unwrap();
+    assert_eq!(serde_json::to_value(received).unwrap(), json_rpc::<serde_json::Value>(response));
+
+    // Untrusted callers are refused during the handshake
+    let settings = settings::TmcpSettings {
+        trust_policy: settings::TrustPolicy {
+            deny: vec![alice.identifier().to_string()],
+            ..Default::default()
+        },
+        ..peer_settings()
+    };
+    let server = crate::TmcpWebSocketServer::from_identity(server.identity().clone(), &settings);
+    let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+    let accept = tokio::spawn(async move { server.accept(server_io).await });
+    let url = format!("ws://localhost/mcp?did={}", alice.identifier());
+    let _client = tokio_tungstenite::client_async(url, client_io).await;
+    assert!(matches!(
+        accept.await.unwrap(),
+        Err(crate::errors::TmcpError::UntrustedPeer(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_tcp_transport() {
+    use futures::{SinkExt, StreamExt};
+    use rmcp::{RoleClient, RoleServer};
+    use settings::OpenFailurePolicy;
+
+    let endpoint = || {
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        Url::parse(&format!("tcp://127.0.0.1:{port}")).unwrap()
+    };
+    let alice = OwnedVid::new_did_peer(endpoint());
+    let bob = OwnedVid::new_did_peer(endpoint());
+    let (alice_wallet, bob_wallet) = verified_pair(&alice, &bob);
+    let (alice_did, bob_did) = (alice.identifier().to_string(), bob.identifier().to_string());
+    let request = r#"{"jsonrpc":"2.0","id":1,"method":"ping"}"#;
+    let response = r#"{"jsonrpc":"2.0","id":1,"result":{}}"#;
+
+    let (mut server_sink, mut server_stream) =
+        crate::tcp::transport::<RoleServer>(bob_wallet, bob_did.clone(), alice_did.clone(), OpenFailurePolicy::Drop)
+            .await
+            .unwrap();
+    let (mut client_sink, mut client_stream) =
+        crate::tcp::transport::<RoleClient>(alice_wallet, alice_did, bob_did, OpenFailurePolicy::Drop)
+            .await
+            .unwrap();
+    client_sink.send(json_rpc(request)).await.unwrap();
+    let received = server_stream.next().await.unwrap();
+    assert_eq!(serde_json::to_value(received).unwrap(), json_rpc::<serde_json::Value>(request));
+    server_sink.send(json_rpc(response)).await.unwrap();
+    let received = client_stream.next().await.unwrap();
+    assert_eq!(serde_json::to_value(received).unwrap(), json_rpc::<serde_json::Value>(response));
+}
+
+#[tokio::test]
+async fn test_tmcp_server() {
+    use bytes::Bytes;
+    use futures::StreamExt;
+    use http::header::{ACCEPT, CONTENT_TYPE};
+    use http_body_util::{BodyExt, Full};
+    use rmcp::{
+        ErrorData, RoleServer, ServerHandler,
+        model::{CallToolRequestParam, CallToolResult, Content, ServerCapabilities, ServerInfo},
+        service::RequestContext,
+        transport::streamable_http_server::{
+            StreamableHttpServerConfig, StreamableHttpService, session::local::LocalSessionManager,
+        },
+    };
+    use tower::ServiceExt;
+
+    /// Answers every tool call with the DID of the caller
+    #[derive(Clone)]
+    struct Whoami;
+
+    impl ServerHandler for Whoami {
+        fn get_info(&self) -> ServerInfo {
+            ServerInfo {
+                capabilities: ServerCapabilities::builder().enable_tools().build(),
+                ..Default::default()
+            }
+        }
+
+        async fn call_tool(
+            &self,
+            _request: CallToolRequestParam,
+            context: RequestContext<RoleServer>,
+        ) -> Result<CallToolResult, ErrorData> {
+            let sender = crate::SenderDid::from_context(&context).map(|sender| sender.as_str().to_string());
+            Ok(CallToolResult::success(vec![Content::text(sender.unwrap_or_default())]))
+        }
+    }
+
+    let alice = peer_vid();
+    let bob = peer_vid();
+    let stranger = peer_vid();
+    let resolver = Arc::new(InMemoryResolver::new());
+    resolver.insert(alice.vid().clone(), None);
+    resolver.insert(stranger.vid().clone(), None);
+    let (alice_wallet, _) = verified_pair(&alice, &bob);
+    let identity = server_identity(&bob, resolver);
+    let server = |settings: settings::TmcpSettings| {
+        let service = StreamableHttpService::new(
+            || Ok(Whoami),
+            LocalSessionManager::default().into(),
+            StreamableHttpServerConfig {
+                stateful_mode: false,
+                ..Default::default()
+            },
+        );
+        crate::TmcpServer::from_layer(&crate::TmcpLayer::from_identity(identity.clone(), &settings), service)
+    };
+    let request = |did: &str, body: String| {
+        http::Request::builder()
+            .method("POST")
+            .uri(format!("/mcp?did={did}"))
+            .header(CONTENT_TYPE, "application/json")
+            .header(ACCEPT, "application/json, text/event-stream")
+            .body(Full::new(Bytes::from(body)))
+            .unwrap()
+    };
+    let call = r#"{"jsonrpc":"2.0","id":1,"method":"tools/call","params":{"name":"whoami","arguments":{}}}"#;
+    let sealed = || tsp_messages::seal_message(call.to_string(), &alice_wallet, alice.identifier(), bob.identifier()).unwrap();
+
+    // The opened request reaches rmcp with the sender DID, and the SSE response is sealed for it
+    let response = server(peer_settings()).oneshot(request(alice.identifier(), sealed())).await.unwrap();
+    assert_eq!(response.status(), http::StatusCode::OK);
+    assert!(response.headers()[CONTENT_TYPE].as_bytes().starts_with(b"text/event-stream"));
+    let mut events = sse_stream::SseStream::new(response.into_body());
+    let data = loop {
+        if let Some(data) = events.next().await.unwrap().unwrap().data {
+            break data;
+        }
+    };
+    let opened = tsp_messages::open_message(data, &alice_wallet).unwrap().into_generic().unwrap();
+    assert_eq!(opened.sender, bob.identifier());
+    let result: serde_json::Value = serde_json::from_str(&opened.payload).unwrap();
+    assert_eq!(result["id"], 1);
+    assert_eq!(result["result"]["content"][0]["text"], alice.identifier());
+
+    // The sender has to be the caller named in the query
+    let response = server(peer_settings()).oneshot(request(stranger.identifier(), sealed())).await.unwrap();
+    assert_eq!(response.status(), http::StatusCode::UNAUTHORIZED);
+
+    // Bodies that cannot be opened never reach rmcp
+    let response = server(peer_settings()).oneshot(request(alice.identifier(), call.to_string())).await.unwrap();
+    assert_eq!(response.status(), http::StatusCode::BAD_REQUEST);
+
+    // Untrusted callers, and under require_relationship unrelated ones, are refused
+    let untrusting = settings::TmcpSettings {
+        trust_policy: settings::TrustPolicy {
+            deny: vec![alice.identifier().to_string()],
+            ..Default::default()
+        },
+        ..peer_settings()
+    };
+    let response = server(untrusting).oneshot(request(alice.identifier(), sealed())).await.unwrap();
+    assert_eq!(response.status(), http::StatusCode::FORBIDDEN);
+    let requiring = settings::TmcpSettings {
+        require_relationship: true,
+        ..peer_settings()
+    };
+    let response = server(requiring).oneshot(request(alice.identifier(), sealed())).await.unwrap();
+    assert_eq!(response.status(), http::StatusCode::FORBIDDEN);
+
+    // JSON responses are sealed as a whole
+    let result = r#"{"jsonrpc":"2.0","id":1,"result":{}}"#;
+    let json_service = tower::service_fn(move |_request: http::Request<Full<Bytes>>| async move {
+        http::Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(Full::new(Bytes::from(result)))
+    });
+    let layer = crate::TmcpLayer::from_identity(identity.clone(), &peer_settings());
+    let response = tower_layer::Layer::layer(&layer, json_service)
+        .oneshot(request(alice.identifier(), sealed()))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), http::StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let opened = tsp_messages::open_message(String::from_utf8(body.to_vec()).unwrap(), &alice_wallet).unwrap();
+    assert_eq!(opened.into_generic().unwrap().payload, result);
+}
+
+#[tokio::test]
+async fn test_relationship_handshake_with_layer() {
+    use axum::routing::post;
+    use http::header::CONTENT_TYPE;
+
+    let alice = peer_vid();
+    let bob = peer_vid();
+    let server_resolver = Arc::new(InMemoryResolver::new());
+    server_resolver.insert(alice.vid().clone(), None);
+    let settings = settings::TmcpSettings {
+        require_relationship: true,
+        ..peer_settings()
+    };
+    let layer = crate::TmcpLayer::from_identity(server_identity(&bob, server_resolver), &settings);
+    let result = serde_json::json!({"jsonrpc": "2.0", "id": 1, "result": {}});
+    let router = axum::Router::new()
+        .route("/mcp", post({
+            let result = result.clone();
+            || async move { axum::Json(result) }
+        }))
+        .layer(layer.clone());
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let uri = format!("http://{}/mcp?did={}", listener.local_addr().unwrap(), alice.identifier());
+    tokio::spawn(async move { axum::serve(listener, router).await });
+
+    let client_resolver = Arc::new(InMemoryResolver::new());
+    client_resolver.insert(bob.vid().clone(), None);
+    let client = TmcpClient::builder("pigeon", bob.identifier())
+        .settings(settings::TmcpSettings {
+            relationship_handshake: true,
+            ..peer_settings()
+        })
+        .resolver(client_resolver)
+        .wallet(pigeon_wallet(&alice))
+        .pin_store(PinStore::in_memory())
+        .build()
+        .await
+        .unwrap();
+    let ping = || {
+        let ping = r#"{"jsonrpc":"2.0","id":1,"method":"ping"}"#.to_string();
+        let sealed = tsp_messages::seal_message(ping, &client.wallet, alice.identifier(), bob.identifier()).unwrap();
+        reqwest::Client::new()
+            .post(&uri)
+            .header(CONTENT_TYPE, "application/json")
+            .body(sealed)
+            .send()
+    };
+
+    // Under require_relationship callers without a relationship are refused
+    assert_eq!(ping().await.unwrap().status(), reqwest::StatusCode::FORBIDDEN);
+
+    client.ensure_relationship(&uri, None).await.unwrap();
+    assert!(tsp_messages::is_related(&client.wallet, alice.identifier(), bob.identifier()).unwrap());
+    assert!(tsp_messages::is_related(layer.identity().wallet(), bob.identifier(), alice.identifier()).unwrap());
+    // The relationship is found in the wallet from now on
+    client.ensure_relationship(&uri, None).await.unwrap();
+
+    let response = ping().await.unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    let opened = tsp_messages::open_message(response.text().await.unwrap(), &client.wallet).unwrap();
+    let payload: serde_json::Value = serde_json::from_str(&opened.into_generic().unwrap().payload).unwrap();
+    assert_eq!(payload, result);
+}
+
+#[tokio::test]
+async fn test_switch_identifier() {
+    let resolver = Arc::new(InMemoryResolver::new());
+    let [me, old, new, forged] = [(); 4].map(|_| peer_vid());
+    for vid in [&me, &old, &new, &forged] {
+        resolver.insert(vid.vid().clone(), None);
+    }
+    let wallet = pigeon_wallet(&me);
+    let settings = peer_settings();
+    let metadata_path = std::env::temp_dir().join(format!("tmcp-{}.tmcp.json", uuid::Uuid::new_v4()));
+    let build = |pin_store: PinStore| {
+        TmcpClient::builder("pigeon", old.identifier())
+            .settings(settings.clone())
+            .resolver(resolver.clone())
+            .wallet(wallet.clone())
+            .pin_store(pin_store)
+            .metadata_store(MetadataStore::open(&metadata_path).unwrap())
+            .build()
+    };
+
+    // New identifiers are checked against the pins like the original one
+    let pin_store = PinStore::in_memory();
+    pin_store.pin(forged.identifier(), Pin::of(&me)).unwrap();
+    let client = build(pin_store).await.unwrap();
+    assert!(matches!(
+        client.switch_identifier(forged.identifier()).await,
+        Err(crate::errors::TmcpError::PinMismatch(_))
+    ));
+    assert_eq!(client.other_did(), old.identifier());
+
+    client.switch_identifier(new.identifier()).await.unwrap();
+    assert_eq!(client.other_did(), new.identifier());
+
+    // The switch is kept next to the wallet
+    let client = build(PinStore::in_memory()).await.unwrap();
+    assert_eq!(client.other_did(), new.identifier());
+    client.switch_identifier(old.identifier()).await.unwrap();
+    let client = build(PinStore::in_memory()).await.unwrap();
+    assert_eq!(client.other_did(), old.identifier());
+    assert!(wallet.export().unwrap().1.keys().all(|alias| !alias.starts_with("tmcp:")));
+    std::fs::remove_file(&metadata_path).unwrap();
+}
+
+#[tokio::test]
+async fn test_in_memory_resolver() {
+    let peer = peer_vid();
+    let resolver = InMemoryResolver::new();
+    resolver.insert(peer.vid().clone(), None);
+
+    let wallet = AsyncSecureStore::new();
+    verify::verify_did(peer.identifier(), &wallet, Some("peer".to_string()), &resolver)
+        .await
+        .unwrap();
+    assert!(wallet.has_verified_vid(peer.identifier()).unwrap());
+    assert_eq!(
+        wallet.resolve_alias("peer").unwrap(),
+        Some(peer.identifier().to_string())
+    );
+
+    assert!(
+        verify::verify_did("did:web:unknown.example", &wallet, None, &resolver)
+            .await
+            .is_err()
+    );
+}
+
+#[cfg(feature = "did-server")]
+#[tokio::test]
+async fn test_local_did_server() {
+    use crate::{DidResolver, did_server::LocalDidServer};
+
+    let server = LocalDidServer::start("127.0.0.1:0").await.unwrap();
+    let did = format!(
+        "did:web:{}:endpoint:alice",
+        server.did_server().replace(":", "%3A")
+    );
+    let alice = OwnedVid::bind(&did, Url::parse("tcp://127.0.0.1:1337").unwrap());
+
+    let client = reqwest::Client::new();
+    let response = client
+        .post(format!("http://{}/add-vid", server.did_server()))
+        .json(alice.vid())
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+
+    let document: serde_json::Value = client
+        .get(format!("http://{}/endpoint/alice/did.json", server.did_server()))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(document["id"], did);
+
+    let (vid, _) = server.resolver().resolve(&did).await.unwrap();
+    assert_eq!(vid.identifier(), did);
+}
+
+#[cfg(feature = "did-server")]
+#[tokio::test]
+async fn test_tmcp_client_with_local_did_server() {
+    use crate::did_server::LocalDidServer;
+
+    let server = LocalDidServer::start("127.0.0.1:0").await.unwrap();
+    let wallet_path = std::env::temp_dir().join(format!("tmcp-{}.sqlite", uuid::Uuid::new_v4()));
+    let settings = settings::TmcpSettings {
+        did_server: server.did_server(),
+        did_server_scheme: "http".to_string(),
+        did_type: settings::DidType::Web,
+        use_webvh: false,
+        wallet_url: format!("sqlite://{}", wallet_path.display()),
+        create_identity: true,
+        ..Default::default()
+    };
+
+    let other = OwnedVid::bind(
+        &settings.did_web("mcp-server"),
+        Url::parse("tcp://127.0.0.1:1337").unwrap(),
+    );
+    reqwest::Client::new()
+        .post(settings.publish_url())
+        .json(other.vid())
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    let client = TmcpClient::new_with_resolver(
+        "pigeon",
+        other.identifier(),
+        settings.clone(),
+        Arc::new(server.resolver()),
+    )
+    .await
+    .unwrap();
+    assert!(client.my_did.starts_with(&settings.did_web("pigeon-")));
+
+    let vault = AskarSecureStorage::open(&settings.wallet_url, settings.wallet_password.as_bytes())
+        .await
+        .unwrap();
+    let (vids, aliases, keys) = vault.read().await.unwrap();
+    assert_eq!(aliases.get("pigeon"), Some(&client.my_did));
+    let store = SecureStore::new();
+    store.import(vids, aliases, keys).unwrap();
+    assert!(store.has_private_vid(&client.my_did).unwrap());
+    assert!(store.has_verified_vid(other.identifier()).unwrap());
+    vault.destroy().await.unwrap();
+}
+
+/// The default resolver reaches the plain-HTTP local DID server like the DID server client does
+#[cfg(feature = "did-server")]
+#[tokio::test]
+async fn test_tmcp_client_new_with_local_did_server() {
+    use crate::did_server::LocalDidServer;
+
+    let server = LocalDidServer::start("127.0.0.1:0").await.unwrap();
+    let settings = settings::TmcpSettings {
+        did_server: server.did_server(),
+        did_server_scheme: "http".to_string(),
+        did_type: settings::DidType::Web,
+        wallet_type: settings::WalletType::InMemory,
+        create_identity: true,
+        ..peer_settings()
+    };
+    let other = OwnedVid::bind(
+        &settings.did_web("mcp-server"),
+        Url::parse("tcp://127.0.0.1:1337").unwrap(),
+    );
+    reqwest::Client::new()
+        .post(settings.publish_url())
+        .json(other.vid())
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    let client = TmcpClient::new("pigeon", other.identifier(), settings.clone())
+        .await
+        .unwrap();
+    assert!(client.my_did.starts_with(&settings.did_web("pigeon-")));
+    assert!(client.wallet.has_verified_vid(other.identifier()).unwrap());
+}
+
+#[tokio::test]
+async fn test_encrypted_file_storage() {
+    let path = std::env::temp_dir().join(format!("tmcp-{}.wallet.json", uuid::Uuid::new_v4()));
+    let storage = EncryptedFileStorage::new(&path, "unsecure");
+    let (vids, aliases, keys) = storage.load().await.unwrap();
+    assert!(vids.is_empty() && aliases.is_empty() && keys.is_empty());
+
+    let vid = peer_vid();
+    let did = vid.identifier().to_string();
+    storage.save(pigeon_wallet(&vid).export().unwrap()).await.unwrap();
+
+    let (vids, aliases, keys) = storage.load().await.unwrap();
+    assert_eq!(aliases.get("pigeon"), Some(&did));
+    let restored = AsyncSecureStore::new();
+    restored.import(vids, aliases, keys).unwrap();
+    assert!(restored.has_private_vid(&did).unwrap());
+
+    assert!(EncryptedFileStorage::new(&path, "wrong").load().await.is_err());
+
+    // A damaged file is an error, not a panic
+    let mut file: serde_json::Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+    file["nonce"] = "AAAA".into();
+    std::fs::write(&path, file.to_string()).unwrap();
+    assert!(matches!(
+        storage.load().await,
+        Err(crate::errors::TmcpError::WalletStorage(_))
+    ));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_persister_saves_after_delay() {
+    let storage = Arc::new(InMemoryStorage::default());
+    let wallet = AsyncSecureStore::new();
+    let persister = Persister::new(storage.clone(), wallet.clone(), Duration::from_millis(50));
+
+    let vid = peer_vid();
+    let did = vid.identifier().to_string();
+    wallet.add_private_vid(vid, None).unwrap();
+    wallet.set_alias("pigeon".to_string(), did.clone()).unwrap();
+    persister.changed();
+    tokio::time::sleep(Duration::from_millis(40)).await;
+    persister.changed();
+    // The clock is paused, so only the coalescing delay decides when the wallet is saved
+    tokio::time::sleep(Duration::from_millis(40)).await;
+    assert!(storage.load().await.unwrap().1.is_empty());
+
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert_eq!(storage.load().await.unwrap().1.get("pigeon"), Some(&did));
+}
+
+#[cfg(feature = "did-server")]
+#[tokio::test]
+async fn test_webvh_rotation_with_local_did_server() {
+    use crate::{DidResolver, did_server::LocalDidServer};
+
+    let server = LocalDidServer::start("127.0.0.1:0").await.unwrap();
+    let resolver = server.resolver();
+    let settings = settings::TmcpSettings {
+        did_server: server.did_server(),
+        did_server_scheme: "http".to_string(),
+        did_type: settings::DidType::Webvh,
+        create_identity: true,
+        ..Default::default()
+    };
+    let client = reqwest::Client::new();
+    let mut wallet = AsyncSecureStore::new();
+    let metadata = MetadataStore::in_memory();
+    let (did, _) = wallet::provision("pigeon", &mut wallet, &metadata, &settings, &client, &resolver)
+        .await
+        .unwrap();
+    let (created, _) = resolver.resolve(&did).await.unwrap();
+
+    // An unpublished rotation is dropped
+    webvh::prepare_rotation(&did, &wallet, &metadata, &settings, &client, &resolver)
+        .await
+        .unwrap();
+    assert!(metadata.has_pending_rotation(&did));
+    webvh::finish_rotation(&did, &wallet, &metadata, &resolver).await.unwrap();
+    assert!(!metadata.has_pending_rotation(&did));
+    let (unchanged, _) = resolver.resolve(&did).await.unwrap();
+    assert_eq!(Pin::of(&unchanged), Pin::of(&created));
+
+    let rotation = webvh::prepare_rotation(&did, &wallet, &metadata, &settings, &client, &resolver)
+        .await
+        .unwrap();
+    webvh::publish_rotation(rotation, &wallet, &metadata, &settings, &client)
+        .await
+        .unwrap();
+    assert!(!metadata.has_pending_rotation(&did));
+    assert!(wallet.export().unwrap().1.keys().all(|alias| !alias.starts_with("tmcp:")));
+
+    let log = webvh::fetch_log(&client, &settings, &did).await.unwrap();
+    webvh::verify_log(&did, &log).unwrap();
+    let (rotated, _) = resolver.resolve(&did).await.unwrap();
+    assert_ne!(Pin::of(&rotated), Pin::of(&created));
+
+    // A peer that only knows the published keys can exchange messages with the wallet
+    let peer = peer_vid();
+    let peer_wallet = AsyncSecureStore::new();
+    peer_wallet.add_private_vid(peer.clone(), None).unwrap();
+    peer_wallet.add_verified_vid(rotated, None).unwrap();
+    wallet.add_verified_vid(peer.vid().clone(), None).unwrap();
+
+    let mut request = tsp_messages::seal_bytes(b"ping", &peer_wallet, peer.identifier(), &did).unwrap();
+    let opened = tsp_messages::open_bytes(&mut request, &wallet).unwrap();
+    assert_eq!(opened.into_generic().unwrap().payload, "ping");
+    let mut response = tsp_messages::seal_bytes(b"pong", &wallet, &did, peer.identifier()).unwrap();
+    let opened = tsp_messages::open_bytes(&mut response, &peer_wallet).unwrap();
+    assert_eq!(opened.sender(), Some(did.as_str()));
+    assert_eq!(opened.into_generic().unwrap().payload, "pong");
+}
+
+#[tokio::test]
+async fn test_retired_identity_is_refused() {
+    let vid = peer_vid();
+    let did = vid.identifier().to_string();
+    let mut wallet = pigeon_wallet(&vid);
+    let metadata = MetadataStore::in_memory();
+    metadata.retire(&did).unwrap();
+
+    let result = wallet::provision(
+        "pigeon",
+        &mut wallet,
+        &metadata,
+        &peer_settings(),
+        &reqwest::Client::new(),
+        &InMemoryResolver::new(),
+    )
+    .await;
+    assert!(matches!(result, Err(crate::errors::TmcpError::RetiredIdentity(retired)) if retired == did));
+}
+
+#[test]
+fn test_webvh_pre_rotation() {
+    let current = ed25519_dalek::SigningKey::from_bytes(&[1u8; 32]);
+    let next = ed25519_dalek::SigningKey::from_bytes(&[2u8; 32]);
+    let log = vec![serde_json::json!({
+        "versionId": "1-QmFirst",
+        "parameters": {
+            "updateKeys": [webvh::multikey(&current)],
+            "nextKeyHashes": [webvh::key_hash(&next)],
+        },
+        "state": {},
+    })];
+
+    // Holding only the current update key is not enough once next keys are committed
+    let wallet = AsyncSecureStore::new();
+    wallet.add_secret_key("current".to_string(), current.to_bytes().to_vec()).unwrap();
+    assert!(webvh::authorize(&log, &wallet, true).is_err());
+
+    wallet.add_secret_key("next".to_string(), next.to_bytes().to_vec()).unwrap();
+    let (update_key, changes) = webvh::authorize(&log, &wallet, true).unwrap();
+    assert_eq!(update_key, next);
+    assert_eq!(changes["updateKeys"], serde_json::json!([webvh::multikey(&next)]));
+    let committed = changes["nextKeyHashes"][0].as_str().unwrap();
+    let (_, _, keys) = wallet.export().unwrap();
+    assert!(keys.values().any(|secret| {
+        webvh::signing_key(secret).is_some_and(|key| webvh::key_hash(&key) == committed)
+    }));
+}
+
+#[test]
+fn test_webvh_verify_log() {
+    let update_key = ed25519_dalek::SigningKey::from_bytes(&[3u8; 32]);
+    let (did, first) = first_log_entry("example.com", &update_key);
+    let mut log = vec![first];
+    let state = serde_json::json!({ "id": did, "rotated": true });
+    log.push(webvh::next_entry(&log, Default::default(), state, &update_key).unwrap());
+    webvh::verify_log(&did, &log).unwrap();
+
+    let mut rewritten = log.clone();
+    rewritten[1]["state"]["rotated"] = false.into();
+    assert!(webvh::verify_log(&did, &rewritten).is_err());
+
+    let intruder = ed25519_dalek::SigningKey::from_bytes(&[4u8; 32]);
+    let mut forged = log[..1].to_vec();
+    let state = serde_json::json!({ "id": did });
+    forged.push(webvh::next_entry(&forged, Default::default(), state, &intruder).unwrap());
+    assert!(webvh::verify_log(&did, &forged).is_err());
+
+    // The log must be the one of the DID: same SCID, and the DID in every state
+    let (other, _) = first_log_entry("example.com", &intruder);
+    assert!(webvh::verify_log(&other, &log).is_err());
+    assert!(webvh::verify_log(&did.replace("example.com", "evil.org"), &log).is_err());
+    let mut renamed = log.clone();
+    renamed[0]["state"]["id"] = other.into();
+    assert!(webvh::verify_log(&did, &renamed).is_err());
+}
+
+#[test]
+fn test_webvh_log_url() {
+    let settings = settings::TmcpSettings {
+        did_server: "127.0.0.1:8080".to_string(),
+        did_server_scheme: "http".to_string(),
+        ..Default::default()
+    };
+    assert_eq!(
+        webvh::log_url(&settings, "did:webvh:QmScid:example.com:endpoint:tmcp").unwrap(),
+        "https://example.com/endpoint/tmcp/did.jsonl"
+    );
+    assert_eq!(
+        webvh::log_url(&settings, "did:webvh:QmScid:example.com").unwrap(),
+        "https://example.com/.well-known/did.jsonl"
+    );
+    assert_eq!(
+        webvh::log_url(&settings, "did:webvh:QmScid:127.0.0.1%3A8080:endpoint:tmcp").unwrap(),
+        "http://127.0.0.1:8080/endpoint/tmcp/did.jsonl"
+    );
+    assert!(webvh::log_url(&settings, "did:web:example.com").is_err());
+}
+
+#[tokio::test]
+async fn test_peer_history() {
+    use crate::peers;
+
+    let update_key = ed25519_dalek::SigningKey::from_bytes(&[5u8; 32]);
+    let (did, first) = first_log_entry("example.com:endpoint:tmcp", &update_key);
+    let did = did.as_str();
+    let mut log = vec![first];
+    let resolver = InMemoryResolver::new();
+    resolver.insert_log(did, log.clone());
+
+    let settings = settings::TmcpSettings::default();
+    let client = reqwest::Client::new();
+    let metadata = MetadataStore::in_memory();
+    let endpoint = Url::parse("tcp://127.0.0.1:1337").unwrap();
+    let first_keys = OwnedVid::bind(did, endpoint.clone());
+    peers::check_peer(first_keys.vid(), &metadata, &settings, &client, &resolver)
+        .await
+        .unwrap();
+
+    // New keys need a new log entry
+    let second_keys = OwnedVid::bind(did, endpoint);
+    assert!(matches!(
+        peers::check_peer(second_keys.vid(), &metadata, &settings, &client, &resolver).await,
+        Err(crate::errors::TmcpError::PeerKeysChanged(_))
+    ));
+    let state = serde_json::json!({ "id": did, "rotated": true });
+    log.push(webvh::next_entry(&log, Default::default(), state, &update_key).unwrap());
+    resolver.insert_log(did, log.clone());
+    peers::check_peer(second_keys.vid(), &metadata, &settings, &client, &resolver)
+        .await
+        .unwrap();
+
+    // The version seen last must stay in the log
+    resolver.insert_log(did, log[..1].to_vec());
+    assert!(matches!(
+        peers::check_peer(second_keys.vid(), &metadata, &settings, &client, &resolver).await,
+        Err(crate::errors::TmcpError::PeerLogRewritten { .. })
+    ));
+
+    // A log that cannot be fetched is refused, unless the policy only warns
+    let (unpublished, _) = first_log_entry("example.com:endpoint:other", &update_key);
+    let unpublished = OwnedVid::bind(&unpublished, Url::parse("tcp://127.0.0.1:1337").unwrap());
+    assert!(
+        peers::check_peer(unpublished.vid(), &metadata, &settings, &client, &resolver)
+            .await
+            .is_err()
+    );
+    let warn = settings::TmcpSettings {
+        peer_history_policy: settings::PeerHistoryPolicy::Warn,
+        ..Default::default()
+    };
+    peers::check_peer(unpublished.vid(), &metadata, &warn, &client, &resolver)
+        .await
+        .unwrap();
+
+    // Without a log there is no history, key changes are left to the pins
+    let peer = peer_vid();
+    peers::check_peer(peer.vid(), &metadata, &settings, &client, &resolver)
+        .await
+        .unwrap();
+}
+
+#[test]
+fn test_pin_store() {
+    let path = std::env::temp_dir().join(format!("tmcp-{}.pins.json", uuid::Uuid::new_v4()));
+    let first = peer_vid();
+    let swapped = OwnedVid::bind(first.identifier(), Url::parse("tcp://127.0.0.1:1337").unwrap());
+    let did = first.identifier();
+
+    let pins = PinStore::open(&path).unwrap();
+    pins.check(did, Pin::of(&first), None).unwrap();
+    pins.check(did, Pin::of(&first), None).unwrap();
+    assert!(matches!(
+        pins.check(did, Pin::of(&swapped), None),
+        Err(crate::errors::TmcpError::PinMismatch(_))
+    ));
+
+    // Pins survive reopening, and a callback can accept the new keys
+    let pins = PinStore::open(&path).unwrap();
+    let accept: crate::pins::PinMismatchCallback = Arc::new(|_, _, _| true);
+    pins.check(did, Pin::of(&swapped), Some(&accept)).unwrap();
+    assert_eq!(PinStore::open(&path).unwrap().get(did), Some(Pin::of(&swapped)));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn test_repin_accepts_new_keys() {
+    let wallet_path = std::env::temp_dir().join(format!("tmcp-{}.wallet", uuid::Uuid::new_v4()));
+    let settings = settings::TmcpSettings {
+        wallet_type: settings::WalletType::EncryptedFile,
+        wallet_url: format!("file://{}", wallet_path.display()),
+        use_webvh: false,
+        ..Default::default()
+    };
+    let resolver = Arc::new(InMemoryResolver::new());
+    let me = peer_vid();
+    resolver.insert(me.vid().clone(), None);
+    crate::storage::open(&settings)
+        .await
+        .unwrap()
+        .save(pigeon_wallet(&me).export().unwrap())
+        .await
+        .unwrap();
+
+    let update_key = ed25519_dalek::SigningKey::from_bytes(&[6u8; 32]);
+    let (did, first) = first_log_entry("example.com:endpoint:tmcp", &update_key);
+    let did = did.as_str();
+    resolver.insert_log(did, vec![first]);
+    let endpoint = Url::parse("tcp://127.0.0.1:1338").unwrap();
+    resolver.insert(OwnedVid::bind(did, endpoint.clone()).vid().clone(), None);
+    let build = || {
+        crate::TmcpClientBuilder::new("pigeon", did)
+            .settings(settings.clone())
+            .resolver(resolver.clone())
+            .build()
+    };
+    build().await.unwrap();
+
+    // Keys changed without a log entry are refused until they are repinned
+    resolver.insert(OwnedVid::bind(did, endpoint).vid().clone(), None);
+    assert!(matches!(build().await, Err(crate::errors::TmcpError::PinMismatch(_))));
+    crate::pins::repin_with_resolver(did, &settings, resolver.as_ref())
+        .await
+        .unwrap();
+    build().await.unwrap();
+
+    std::fs::remove_file(&wallet_path).unwrap();
+    std::fs::remove_file(format!("{}.pins.json", wallet_path.display())).unwrap();
+    std::fs::remove_file(format!("{}.tmcp.json", wallet_path.display())).unwrap();
+}
+
+#[test]
+fn test_trust_policy() {
+    let policy = settings::TrustPolicy {
+        allow: vec![
+            "did:webvh:*:example.com:**".to_string(),
+            "did:web:partner.org:endpoint:mcp".to_string(),
+        ],
+        deny: vec!["did:webvh:*:example.com:endpoint:revoked".to_string()],
+    };
+    assert!(policy.allows("did:webvh:QmScid:example.com:endpoint:tmcp"));
+    assert!(policy.allows("did:web:partner.org:endpoint:mcp"));
+    assert!(!policy.allows("did:web:partner.org:endpoint:other"));
+    assert!(!policy.allows("did:webvh:QmScid:example.com.evil.org:endpoint:tmcp"));
+    assert!(!policy.allows("did:webvh:QmX:evil.org:example.com:endpoint:x"));
+    assert!(!policy.allows("did:webvh:QmScid:example.com:endpoint:revoked"));
+    assert!(matches!(
+        policy.check("did:peer:2.Vz6Mk"),
+        Err(crate::errors::TmcpError::UntrustedPeer(_))
+    ));
+    assert!(settings::TrustPolicy::default().allows("did:peer:2.Vz6Mk"));
+}
+
+#[tokio::test]
+async fn test_recover_identity_from_backup() {
+    let backup_path = std::env::temp_dir().join(format!("tmcp-{}.backup.json", uuid::Uuid::new_v4()));
+    let vid = peer_vid();
+    let did = vid.identifier().to_string();
+    let resolver = InMemoryResolver::new();
+    resolver.insert(vid.vid().clone(), None);
+    let backup = AsyncSecureStore::new();
+    backup.add_private_vid(vid, None).unwrap();
+    EncryptedFileStorage::new(&backup_path, "unsecure")
+        .save(backup.export().unwrap())
+        .await
+        .unwrap();
+
+    let settings = peer_settings();
+    let client = reqwest::Client::new();
+    let mut wallet = AsyncSecureStore::new();
+    let metadata = MetadataStore::in_memory();
+    let result = wallet::provision("pigeon", &mut wallet, &metadata, &settings, &client, &resolver).await;
+    assert!(matches!(result, Err(crate::errors::TmcpError::NoIdentity(_))));
+
+    let settings = settings::TmcpSettings {
+        did: Some(did.clone()),
+        key_backup_path: Some(backup_path.display().to_string()),
+        ..settings
+    };
+    let (my_did, recovered) =
+        wallet::provision("pigeon", &mut wallet, &metadata, &settings, &client, &resolver)
+            .await
+            .unwrap();
+    assert_eq!(my_did, did);
+    assert!(recovered);
+    assert!(wallet.has_private_vid(&did).unwrap());
+    assert_eq!(wallet.resolve_alias("pigeon").unwrap(), Some(did));
+    std::fs::remove_file(&backup_path).unwrap();
+}
@@ -0,0 +1,137 @@
+//! Helpers shared by the message based TMCP transports (stdio, WebSocket).
+//!
+//! Each transport is a sink/stream pair, which rmcp accepts wherever a transport is expected, e.g.
+//! `client_info.serve((sink, stream))`.
+
+use std::pin::Pin;
+
+use futures::{Sink, Stream, StreamExt, future, stream::BoxStream};
+use rmcp::service::{RxJsonRpcMessage, ServiceRole, TxJsonRpcMessage};
+use serde::{Serialize, de::DeserializeOwned};
+use tsp_sdk::AsyncSecureStore;
+
+use crate::{
+    errors::TmcpError,
+    settings::OpenFailurePolicy,
+    tsp_messages::{self, TspMessage},
+};
+
+/// Sink sealing outgoing JSON-RPC messages for the peer
+pub type TmcpSink<R> = Pin<Box<dyn Sink<TxJsonRpcMessage<R>, Error = TmcpError> + Send>>;
+/// Stream of JSON-RPC messages opened from the peer
+pub type TmcpStream<R> = BoxStream<'static, RxJsonRpcMessage<R>>;
+
+/// Serialize and seal a JSON-RPC message for `peer_did`, as URL-safe base64
+#[allow(clippy::result_large_err)]
+pub(crate) fn seal_frame<R>(
+    message: &TxJsonRpcMessage<R>,
+    wallet: &AsyncSecureStore,
+    my_did: &str,
+    peer_did: &str,
+) -> Result<String, TmcpError>
+where
+    R: ServiceRole,
+    TxJsonRpcMessage<R>: Serialize,
+{
+    let json_str = serde_json::to_string(message)?;
+    tsp_messages::seal_message(json_str, wallet, my_did, peer_did)
+}
+
+/// Serialize and seal a JSON-RPC message for `peer_did`, as a raw CESR TSP message
+#[allow(clippy::result_large_err)]
+pub(crate) fn seal_raw_frame<R>(
+    message: &TxJsonRpcMessage<R>,
+    wallet: &AsyncSecureStore,
+    my_did: &str,
+    peer_did: &str,
+) -> Result<Vec<u8>, TmcpError>
+where
+    R: ServiceRole,
+    TxJsonRpcMessage<R>: Serialize,
+{
+    let json = serde_json::to_vec(message)?;
+    tsp_messages::seal_bytes(&json, wallet, my_did, peer_did)
+}
+
+/// Open a URL-safe base64 frame and parse the JSON-RPC message inside.
+///
+/// Frames that were not sent by `peer_did`, or do not hold a JSON-RPC message, are logged and
+/// dropped (`None`). Frames that cannot be opened are handled according to `on_failure`: dropped,
+/// or returned as an error under [`OpenFailurePolicy::FailStream`], see [`until_failed`].
+pub(crate) fn open_frame<R>(
+    data: String,
+    wallet: &AsyncSecureStore,
+    peer_did: &str,
+    on_failure: OpenFailurePolicy,
+) -> Option<Result<RxJsonRpcMessage<R>, TmcpError>>
+where
+    R: ServiceRole,
+    RxJsonRpcMessage<R>: DeserializeOwned,
+{
+    accept_opened(tsp_messages::open_message(data, wallet), peer_did, on_failure)
+}
+
+/// Open a raw CESR frame and parse the JSON-RPC message inside, see [`open_frame`].
+pub(crate) fn open_raw_frame<R>(
+    mut data: Vec<u8>,
+    wallet: &AsyncSecureStore,
+    peer_did: &str,
+    on_failure: OpenFailurePolicy,
+) -> Option<Result<RxJsonRpcMessage<R>, TmcpError>>
+where
+    R: ServiceRole,
+    RxJsonRpcMessage<R>: DeserializeOwned,
+{
+    accept_opened(tsp_messages::open_bytes(&mut data, wallet), peer_did, on_failure)
+}
+
+/// Passes on the messages of `frames` up to the first one that failed to open
+pub(crate) fn until_failed<M: Send + 'static>(
+    frames: impl Stream<Item = Result<M, TmcpError>> + Send + 'static,
+) -> BoxStream<'static, M> {
+    frames
+        .take_while(|frame| future::ready(frame.is_ok()))
+        .filter_map(|frame| future::ready(frame.ok()))
+        .boxed()
+}
+
+/// Logs a message that failed to open with `e`; under [`OpenFailurePolicy::FailStream`] the error
+/// is passed on to end the stream
+pub(crate) fn open_failed<M>(e: TmcpError, on_failure: OpenFailurePolicy) -> Option<Result<M, TmcpError>> {
+    log::error!("failed to open message: {}", e);
+    match on_failure {
+        OpenFailurePolicy::FailStream => Some(Err(e)),
+        // No request is in flight to answer with a JSON-RPC error
+        OpenFailurePolicy::Drop | OpenFailurePolicy::JsonRpcError => None,
+    }
+}
+
+fn accept_opened<R>(
+    opened: Result<TspMessage, TmcpError>,
+    peer_did: &str,
+    on_failure: OpenFailurePolicy,
+) -> Option<Result<RxJsonRpcMessage<R>, TmcpError>>
+where
+    R: ServiceRole,
+    RxJsonRpcMessage<R>: DeserializeOwned,
+{
+    let opened = match opened {
+        Ok(TspMessage::Generic(opened)) => opened,
+        Ok(control) => {
+            log::debug!("ignoring {} from {:?}", control.kind(), control.sender());
+            return None;
+        }
+        Err(e) => return open_failed(e, on_failure),
+    };
+    if opened.sender != peer_did {
+        log::error!("dropping message from unexpected sender {}", opened.sender);
+        return None;
+    }
+    match serde_json::from_str(&opened.payload) {
+        Ok(message) => Some(Ok(message)),
+        Err(e) => {
+            log::error!("failed to parse message: {}", e);
+            None
+        }
+    }
+}
@@ -1,23 +1,170 @@
-use tsp_sdk::{AsyncSecureStore, ReceivedTspMessage};
+use tsp_sdk::{AsyncSecureStore, ReceivedTspMessage, RelationshipStatus};
 use base64::{engine::general_purpose, Engine as _};
 use crate::errors::{self, TmcpError};
 
+/// The payload of an opened TSP message together with the VIDs from its envelope.
+#[derive(Debug, Clone)]
+pub struct OpenedMessage {
+    /// VID of the sender, authenticated by the envelope signature
+    pub sender: String,
+    /// VID of the intended receiver, if the envelope names one
+    pub receiver: Option<String>,
+    /// The decrypted message
+    pub payload: String,
+}
+
+/// An opened TSP message: either a generic message carrying an MCP payload, or a control message.
+#[derive(Debug, Clone)]
+pub enum TspMessage {
+    /// A message carrying an MCP payload
+    Generic(OpenedMessage),
+    /// The sender asks for a relationship, identified by `thread_id`
+    RelationshipRequest {
+        sender: String,
+        receiver: String,
+        thread_id: [u8; 32],
+    },
+    /// The sender accepted our relationship request
+    RelationshipAccept { sender: String, receiver: String },
+    /// The sender cancelled our relationship
+    RelationshipCancel { sender: String, receiver: String },
+    /// The sender announces `new_vid` as its new identifier
+    NewIdentifier {
+        sender: String,
+        receiver: String,
+        new_vid: String,
+    },
+    /// The sender refers us to `referred_vid`
+    Referral {
+        sender: String,
+        receiver: String,
+        referred_vid: String,
+    },
+    /// Routed and pending messages, which TMCP does not handle
+    Unsupported {
+        sender: Option<String>,
+        kind: &'static str,
+    },
+}
+
+impl TspMessage {
+    /// The VID of the sender, authenticated by the envelope signature
+    pub fn sender(&self) -> Option<&str> {
+        match self {
+            TspMessage::Generic(message) => Some(&message.sender),
+            TspMessage::RelationshipRequest { sender, .. }
+            | TspMessage::RelationshipAccept { sender, .. }
+            | TspMessage::RelationshipCancel { sender, .. }
+            | TspMessage::NewIdentifier { sender, .. }
+            | TspMessage::Referral { sender, .. } => Some(sender),
+            TspMessage::Unsupported { sender, .. } => sender.as_deref(),
+        }
+    }
+
+    /// The generic message, or an error for control messages
+    #[allow(clippy::result_large_err)]
+    pub fn into_generic(self) -> Result<OpenedMessage, TmcpError> {
+        match self {
+            TspMessage::Generic(message) => Ok(message),
+            other => Err(TmcpError::TmcpError(format!(
+                "Expected a generic TSP message, got {}",
+                other.kind()
+            ))),
+        }
+    }
+
+    /// A short name of the message type, for logging
+    pub fn kind(&self) -> &'static str {
+        match self {
+            TspMessage::Generic(_) => "generic message",
+            TspMessage::RelationshipRequest { .. } => "relationship request",
+            TspMessage::RelationshipAccept { .. } => "relationship accept",
+            TspMessage::RelationshipCancel { .. } => "relationship cancel",
+            TspMessage::NewIdentifier { .. } => "new identifier",
+            TspMessage::Referral { .. } => "referral",
+            TspMessage::Unsupported { kind, .. } => kind,
+        }
+    }
+}
+
+impl TryFrom<ReceivedTspMessage> for TspMessage {
+    type Error = TmcpError;
+
+    fn try_from(message: ReceivedTspMessage) -> Result<Self, TmcpError> {
+        Ok(match message {
+            ReceivedTspMessage::GenericMessage {
+                sender,
+                receiver,
+                message,
+                ..
+            } => TspMessage::Generic(OpenedMessage {
+                sender,
+                receiver,
+                payload: String::from_utf8(message.to_vec())?,
+            }),
+            ReceivedTspMessage::RequestRelationship {
+                sender,
+                receiver,
+                thread_id,
+                ..
+            } => TspMessage::RelationshipRequest {
+                sender,
+                receiver,
+                thread_id,
+            },
+            ReceivedTspMessage::AcceptRelationship {
+                sender, receiver, ..
+            } => TspMessage::RelationshipAccept { sender, receiver },
+            ReceivedTspMessage::CancelRelationship {
+                sender, receiver, ..
+            } => TspMessage::RelationshipCancel { sender, receiver },
+            ReceivedTspMessage::NewIdentifier {
+                sender,
+                receiver,
+                new_vid,
+                ..
+            } => TspMessage::NewIdentifier {
+                sender,
+                receiver,
+                new_vid,
+            },
+            ReceivedTspMessage::Referral {
+                sender,
+                receiver,
+                referred_vid,
+                ..
+            } => TspMessage::Referral {
+                sender,
+                receiver,
+                referred_vid,
+            },
+            ReceivedTspMessage::ForwardRequest { sender, .. } => TspMessage::Unsupported {
+                sender: Some(sender),
+                kind: "forward request",
+            },
+            ReceivedTspMessage::PendingMessage { .. } => TspMessage::Unsupported {
+                sender: None,
+                kind: "pending message",
+            },
+        })
+    }
+}
+
 /// Open a TSP message using the given wallet.
 ///
-/// The function takes a URL-safe base64 encoded string as input, decodes it, extracts the sender and receiver from the message, and then uses the wallet to open the message.
+/// The function takes a URL-safe base64 encoded string as input, decodes it, and uses the wallet to open the message.
 ///
-/// If the message is of type `ReceivedTspMessage::GenericMessage`, the function returns the decrypted message as a UTF-8 string. Otherwise, an error of type `TmcpError` is returned with the message "Unsupported TSP message type".
+/// Generic messages are returned as [`TspMessage::Generic`], with the decrypted payload as a UTF-8 string along with the sender and receiver VIDs. Control messages (relationship requests, accepts and cancels, new identifiers, referrals) are returned as their own variants; opening them already updates the relationship state in the wallet.
 #[allow(clippy::result_large_err)]
-pub fn open_message(data: String, wallet: &AsyncSecureStore) -> Result<String, errors::TmcpError> {
+pub fn open_message(data: String, wallet: &AsyncSecureStore) -> Result<TspMessage, errors::TmcpError> {
     let mut data = general_purpose::URL_SAFE.decode(&data)?;
-    let tsp_message = wallet.open_message(&mut data)?;
-    if let ReceivedTspMessage::GenericMessage{
-        message,..
-    } = tsp_message {
-        Ok(String::from_utf8(message.to_vec())?)
-    } else {
-        Err(TmcpError::TmcpError("Unsupported TSP message type".into()))
-    }
+    open_bytes(&mut data, wallet)
+}
+
+/// Open a raw CESR encoded TSP message using the given wallet, see [`open_message`].
+#[allow(clippy::result_large_err)]
+pub fn open_bytes(data: &mut [u8], wallet: &AsyncSecureStore) -> Result<TspMessage, errors::TmcpError> {
+    wallet.open_message(data)?.try_into()
 }
 
 /// Seal a message using the TSP SDK, returning the sealed message as a URL-safe base64-encoded string.
@@ -26,7 +173,7 @@ pub fn open_message(data: String, wallet: &AsyncSecureStore) -> Result<String, e
 ///
 /// The sealed message is then encoded as a URL-safe base64 string using the `general_purpose::URL_SAFE` engine.
 ///
-/// If the sealing process fails, an error of type `TmcpError` is returned.
+/// If the sealing process fails, a `TmcpError::SealFailed` naming the peer DID and the reason is returned.
 ///
 /// # Arguments
 ///
@@ -34,6 +181,59 @@ pub fn open_message(data: String, wallet: &AsyncSecureStore) -> Result<String, e
 /// * `wallet`: A reference to an `AsyncSecureStore` instance, used to perform the sealing operation
 #[allow(clippy::result_large_err)]
 pub fn seal_message(data: String, wallet: &AsyncSecureStore, my_did: &str, other_did: &str) -> Result<String, errors::TmcpError> {
-    let (_url, data) = wallet.seal_message(my_did, other_did, None, &data.into_bytes())?;
+    let data = seal_bytes(data.as_bytes(), wallet, my_did, other_did)?;
     Ok(general_purpose::URL_SAFE.encode(&data))
-}
\ No newline at end of file
+}
+
+/// Seal a message using the TSP SDK, returning the raw CESR encoded TSP message.
+///
+/// Fails with [`TmcpError::SealFailed`], e.g. if `other_did` was never verified or our keys are missing.
+#[allow(clippy::result_large_err)]
+pub fn seal_bytes(data: &[u8], wallet: &AsyncSecureStore, my_did: &str, other_did: &str) -> Result<Vec<u8>, errors::TmcpError> {
+    let (_url, data) = wallet
+        .seal_message(my_did, other_did, None, data)
+        .map_err(|e| TmcpError::SealFailed {
+            peer_did: other_did.to_string(),
+            reason: e.to_string(),
+        })?;
+    Ok(data)
+}
+
+/// Seal a relationship request from `my_did` to `other_did`, as a URL-safe base64 string.
+///
+/// The wallet records the relationship as pending until the accept is opened.
+#[allow(clippy::result_large_err)]
+pub fn relationship_request(wallet: &AsyncSecureStore, my_did: &str, other_did: &str) -> Result<String, errors::TmcpError> {
+    let (_url, data) = wallet.make_relationship_request(my_did, other_did, None)?;
+    Ok(general_purpose::URL_SAFE.encode(&data))
+}
+
+/// Seal the accept of the relationship request `thread_id` of `other_did`, as a URL-safe base64 string.
+#[allow(clippy::result_large_err)]
+pub fn relationship_accept(
+    wallet: &AsyncSecureStore,
+    my_did: &str,
+    other_did: &str,
+    thread_id: [u8; 32],
+) -> Result<String, errors::TmcpError> {
+    let (_url, data) = wallet.make_relationship_accept(my_did, other_did, thread_id, None)?;
+    Ok(general_purpose::URL_SAFE.encode(&data))
+}
+
+/// Seal the cancellation of the relationship with `other_did`, as a URL-safe base64 string.
+///
+/// The wallet drops the relationship right away.
+#[allow(clippy::result_large_err)]
+pub fn relationship_cancel(wallet: &AsyncSecureStore, my_did: &str, other_did: &str) -> Result<String, errors::TmcpError> {
+    let (_url, data) = wallet.make_relationship_cancel(my_did, other_did)?;
+    Ok(general_purpose::URL_SAFE.encode(&data))
+}
+
+/// Whether the wallet holds an accepted relationship between `my_did` and `other_did`
+#[allow(clippy::result_large_err)]
+pub fn is_related(wallet: &AsyncSecureStore, my_did: &str, other_did: &str) -> Result<bool, errors::TmcpError> {
+    Ok(matches!(
+        wallet.get_relation_status_for_vid_pair(my_did, other_did)?,
+        RelationshipStatus::Bidirectional { .. }
+    ))
+}
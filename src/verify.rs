@@ -1,15 +1,19 @@
 use serde_json::Value;
-use tsp_sdk::{AsyncSecureStore, Error, VerifiedVid};
+use tsp_sdk::{AsyncSecureStore, VerifiedVid, Vid};
+
+use crate::{errors::TmcpError, resolver::DidResolver};
 
 /// Resolve and verify public key material for a VID identified by vid and add it to the wallet as a relationship
 ///
 /// This function will verify the DID document and store it in the wallet with the given alias.
+/// DIDs the wallet holds private keys for are only checked, so their keys are kept.
 ///
 /// # Parameters
 ///
 /// * vid - The DID document to verify and store.
 /// * wallet - The wallet in which to store the DID document.
 /// * alias - The alias with which to store the DID document in the wallet.
+/// * resolver - The resolver used to resolve the DID.
 ///
 /// # Returns
 ///
@@ -18,10 +22,28 @@ pub async fn verify_did(
     did: &str,
     wallet: &AsyncSecureStore,
     alias: Option<String>,
-) -> Result<Option<Value>, Error> {
-    //Resolve and verify the vid identified by id, by using online and offline methods
-    let (vid, metadata) = tsp_sdk::vid::verify_vid(did).await?;
-    //Resolve and verify public key material for a VID identified by vid and add it to the wallet as a relationship
-    wallet.verify_vid(vid.identifier(), alias).await?;
+    resolver: &dyn DidResolver,
+) -> Result<Option<Value>, TmcpError> {
+    //Resolve and verify the vid identified by id
+    let (vid, metadata) = resolver.resolve(did).await?;
+    store_verified(vid, metadata, wallet, alias)
+}
+
+/// Adds the public key material of an already resolved `vid` to the wallet, like [`verify_did`]
+#[allow(clippy::result_large_err)]
+pub(crate) fn store_verified(
+    vid: Vid,
+    metadata: Option<Value>,
+    wallet: &AsyncSecureStore,
+    alias: Option<String>,
+) -> Result<Option<Value>, TmcpError> {
+    let did = vid.identifier().to_string();
+    if !wallet.has_private_vid(&did)? {
+        //Add the verified public key material to the wallet as a relationship
+        wallet.add_verified_vid(vid, metadata.clone())?;
+    }
+    if let Some(alias) = alias {
+        wallet.set_alias(alias, did)?;
+    }
     Ok(metadata)
 }
@@ -0,0 +1,160 @@
+use std::sync::Arc;
+
+use tsp_sdk::{AsyncSecureStore, VerifiedVid};
+
+use crate::{
+    create::create,
+    errors::TmcpError,
+    get,
+    metadata::MetadataStore,
+    resolver::DidResolver,
+    settings::TmcpSettings,
+    storage::{self, EncryptedFileStorage, WalletStorage},
+    verify, webvh,
+};
+
+/// A wallet read from its storage, together with the DID it acts as.
+pub(crate) struct LoadedWallet {
+    pub storage: Arc<dyn WalletStorage>,
+    pub wallet: AsyncSecureStore,
+    pub my_did: String,
+    /// Whether `my_did` was created or recovered during loading and still needs persisting
+    pub created: bool,
+}
+
+/// Opens (or creates) the wallet configured in `settings` and makes sure it holds a DID for `alias`,
+/// see [`provision`]. DIDs are published with `client` and resolved with `resolver`.
+pub(crate) async fn load_wallet(
+    alias: &str,
+    settings: &TmcpSettings,
+    client: &reqwest::Client,
+    resolver: &dyn DidResolver,
+) -> Result<LoadedWallet, TmcpError> {
+    settings.validate()?;
+    let storage = storage::open(settings).await?;
+    let mut wallet = read_wallet(storage.as_ref()).await?;
+    let metadata = MetadataStore::from_settings(settings)?;
+    let (my_did, created) = provision(alias, &mut wallet, &metadata, settings, client, resolver).await?;
+    Ok(LoadedWallet {
+        storage,
+        wallet,
+        my_did,
+        created,
+    })
+}
+
+/// Reads the wallet held by `storage`
+pub(crate) async fn read_wallet(storage: &dyn WalletStorage) -> Result<AsyncSecureStore, TmcpError> {
+    let (vids, aliases, keys) = storage.load().await?;
+    let wallet = AsyncSecureStore::new();
+    wallet.import(vids, aliases, keys)?;
+    Ok(wallet)
+}
+
+/// The wallet alias the DID of `alias` is stored under
+pub(crate) fn wallet_alias(alias: &str, settings: &TmcpSettings) -> String {
+    if settings.use_webvh {
+        format!("{}vh", alias)
+    } else {
+        alias.to_string()
+    }
+}
+
+/// Makes sure `wallet` holds a DID for `alias`, returning it and whether it was newly added.
+///
+/// A DID missing from the wallet is recovered if the settings name it (`did`, or `did_username` to
+/// look it up on the DID server): its keys are restored from `key_backup_path`. Otherwise a new DID
+/// is created, but only with `create_identity`.
+///
+/// Fails with [`TmcpError::RetiredIdentity`] if the DID of `alias` is marked retired in `metadata`.
+pub(crate) async fn provision(
+    alias: &str,
+    wallet: &mut AsyncSecureStore,
+    metadata: &MetadataStore,
+    settings: &TmcpSettings,
+    client: &reqwest::Client,
+    resolver: &dyn DidResolver,
+) -> Result<(String, bool), TmcpError> {
+    let wallet_alias = wallet_alias(alias, settings);
+    if let Some(my_did) = wallet.resolve_alias(&wallet_alias)? {
+        if metadata.is_retired(&my_did) {
+            return Err(TmcpError::RetiredIdentity(my_did));
+        }
+        // Keys of an interrupted rotation are only used if the DID server published them
+        webvh::finish_rotation(&my_did, wallet, metadata, resolver).await?;
+        //Resolve and verify public key material for a VID identified by vid and add it to the wallet as a relationship
+        verify::verify_did(&my_did, wallet, None, resolver).await?;
+        return Ok((my_did, false));
+    }
+
+    let published_did = match (&settings.did, &settings.did_username) {
+        (Some(did), _) => Some(did.clone()),
+        (None, Some(username)) => match get::get_did_doc(client, settings, username).await {
+            Ok(published_did) => Some(published_did),
+            Err(e) => {
+                log::warn!("Warning:unable to get published did of {}: {:?}", username, e);
+                None
+            }
+        },
+        (None, None) => None,
+    };
+    let my_did = if let Some(published_did) = published_did {
+        recover(&published_did, &wallet_alias, wallet, settings).await?;
+        if metadata.is_retired(&published_did) {
+            return Err(TmcpError::RetiredIdentity(published_did));
+        }
+        verify::verify_did(&published_did, wallet, None, resolver).await?;
+        published_did
+    } else if settings.create_identity {
+        let tsp_address = settings.tsp_address.as_deref().unwrap_or(&settings.did_server);
+        let private_vid = create(Some(tsp_address), Some(alias), wallet, settings, client).await?;
+        let my_did = private_vid.identifier().to_string();
+        let meta_data = verify::verify_did(&my_did, wallet, None, resolver).await?;
+        wallet.add_private_vid(private_vid, meta_data)?;
+        write_backup(wallet, settings).await?;
+        my_did
+    } else {
+        return Err(TmcpError::NoIdentity(alias.to_string()));
+    };
+
+    Ok((my_did, true))
+}
+
+/// Restores the private keys of our published `did` from the key backup
+async fn recover(
+    did: &str,
+    wallet_alias: &str,
+    wallet: &AsyncSecureStore,
+    settings: &TmcpSettings,
+) -> Result<(), TmcpError> {
+    let recovery_failed = |reason: &str| TmcpError::RecoveryFailed {
+        did: did.to_string(),
+        reason: reason.to_string(),
+    };
+    let Some(path) = &settings.key_backup_path else {
+        return Err(recovery_failed("no key_backup_path is configured"));
+    };
+    let (vids, aliases, keys) = EncryptedFileStorage::new(path, &settings.wallet_password)
+        .load()
+        .await?;
+    wallet.import(vids, aliases, keys)?;
+    if !wallet.has_private_vid(did)? {
+        return Err(recovery_failed("the key backup holds no private keys for it"));
+    }
+    wallet.set_alias(wallet_alias.to_string(), did.to_string())?;
+    log::info!("recovered {} from the key backup", did);
+    Ok(())
+}
+
+/// Refreshes the key backup at `key_backup_path`, if one is configured
+pub(crate) async fn write_backup(
+    wallet: &AsyncSecureStore,
+    settings: &TmcpSettings,
+) -> Result<(), TmcpError> {
+    if let Some(path) = &settings.key_backup_path {
+        EncryptedFileStorage::new(path, &settings.wallet_password)
+            .save(wallet.export()?)
+            .await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,159 @@
+//! WebSocket transport for TMCP.
+//!
+//! Every binary frame carries one raw CESR encoded TSP message holding a JSON-RPC message. Text
+//! frames are ignored. The client announces its DID with a `did` query parameter on the upgrade
+//! request, just like the HTTP transport.
+
+use std::sync::Arc;
+
+use futures::{SinkExt, StreamExt, future};
+use rmcp::RoleServer;
+use rmcp::service::{RxJsonRpcMessage, ServiceRole, TxJsonRpcMessage};
+use serde::{Serialize, de::DeserializeOwned};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+use tsp_sdk::AsyncSecureStore;
+
+use crate::{
+    errors::TmcpError,
+    identity::ServerIdentity,
+    layer::{self, SenderDid},
+    resolver::DidResolver,
+    settings::{OpenFailurePolicy, TmcpSettings, TrustPolicy},
+    transport::{TmcpSink, TmcpStream, open_raw_frame, seal_raw_frame, until_failed},
+};
+
+/// Connect to a TMCP WebSocket server at `url`, sealing every frame from `my_did` for `peer_did`.
+pub async fn connect<R>(
+    wallet: AsyncSecureStore,
+    my_did: String,
+    peer_did: String,
+    url: &str,
+    on_failure: OpenFailurePolicy,
+) -> Result<(TmcpSink<R>, TmcpStream<R>), TmcpError>
+where
+    R: ServiceRole,
+    TxJsonRpcMessage<R>: Serialize,
+    RxJsonRpcMessage<R>: DeserializeOwned,
+{
+    let (socket, _response) =
+        tokio_tungstenite::connect_async(format!("{}?did={}", url, my_did)).await?;
+    Ok(transport(socket, wallet, my_did, peer_did, on_failure))
+}
+
+/// Builds a TSP protected transport over an established WebSocket; frames that cannot be opened
+/// are handled according to `on_failure`.
+pub fn transport<R, S>(
+    socket: WebSocketStream<S>,
+    wallet: AsyncSecureStore,
+    my_did: String,
+    peer_did: String,
+    on_failure: OpenFailurePolicy,
+) -> (TmcpSink<R>, TmcpStream<R>)
+where
+    R: ServiceRole,
+    TxJsonRpcMessage<R>: Serialize,
+    RxJsonRpcMessage<R>: DeserializeOwned,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (write, read) = socket.split();
+    let seal_wallet = wallet.clone();
+    let open_peer = peer_did.clone();
+    let sink = write
+        .sink_map_err(TmcpError::from)
+        .with(move |message: TxJsonRpcMessage<R>| {
+            future::ready(
+                seal_raw_frame(&message, &seal_wallet, &my_did, &peer_did).map(Message::binary),
+            )
+        });
+    let stream = read.filter_map(move |frame| {
+        let message = match frame {
+            Ok(Message::Binary(data)) => open_raw_frame(data.to_vec(), &wallet, &open_peer, on_failure),
+            Ok(_) => None,
+            Err(e) => {
+                log::error!("failed to read frame: {}", e);
+                None
+            }
+        };
+        future::ready(message)
+    });
+    (Box::pin(sink), until_failed(stream))
+}
+
+/// Accepts TMCP WebSocket connections for a server identity.
+#[derive(Clone)]
+pub struct TmcpWebSocketServer {
+    identity: ServerIdentity,
+    trust_policy: TrustPolicy,
+    open_failure_policy: OpenFailurePolicy,
+}
+
+impl TmcpWebSocketServer {
+    /// Loads (or provisions) the server identity for `alias`, see [`ServerIdentity::load`]
+    pub async fn new(alias: &str, settings: TmcpSettings) -> Result<Self, TmcpError> {
+        let identity = ServerIdentity::load(alias, &settings).await?;
+        Ok(Self::from_identity(identity, &settings))
+    }
+
+    /// Like [`TmcpWebSocketServer::new`], resolving DIDs with `resolver` instead of the network
+    pub async fn new_with_resolver(
+        alias: &str,
+        settings: TmcpSettings,
+        resolver: Arc<dyn DidResolver>,
+    ) -> Result<Self, TmcpError> {
+        let identity = ServerIdentity::load_with_resolver(alias, &settings, resolver).await?;
+        Ok(Self::from_identity(identity, &settings))
+    }
+
+    /// Accepts connections for an already loaded `identity`, applying the trust and open failure
+    /// policies of `settings`
+    pub fn from_identity(identity: ServerIdentity, settings: &TmcpSettings) -> Self {
+        Self {
+            identity,
+            trust_policy: settings.trust_policy.clone(),
+            open_failure_policy: settings.open_failure_policy,
+        }
+    }
+
+    /// The identity callers talk to
+    pub fn identity(&self) -> &ServerIdentity {
+        &self.identity
+    }
+
+    /// Saves pending wallet changes now, see [`ServerIdentity::flush`]
+    pub async fn flush(&self) -> Result<(), TmcpError> {
+        self.identity.flush().await
+    }
+
+    /// Performs the WebSocket handshake on `stream` and returns the caller's DID with a transport
+    /// that can be passed to `ServiceExt::serve`.
+    pub async fn accept<S>(
+        &self,
+        stream: S,
+    ) -> Result<(SenderDid, TmcpSink<RoleServer>, TmcpStream<RoleServer>), TmcpError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let mut caller_did = None;
+        let socket = tokio_tungstenite::accept_hdr_async(stream, |request: &Request, response: Response| {
+            caller_did = layer::caller_did(request.uri());
+            Ok(response)
+        })
+        .await?;
+        let Some(caller_did) = caller_did else {
+            return Err(TmcpError::TmcpError("missing did query parameter".into()));
+        };
+        self.trust_policy.check(&caller_did)?;
+        self.identity.ensure_verified(&caller_did).await?;
+        let (sink, stream) = transport(
+            socket,
+            self.identity.wallet().clone(),
+            self.identity.did().to_string(),
+            caller_did.clone(),
+            self.open_failure_policy,
+        );
+        Ok((SenderDid(caller_did), sink, stream))
+    }
+}
@@ -0,0 +1,583 @@
+//! did:webvh log maintenance.
+//!
+//! `create` publishes the first entry of a did:webvh log and keeps its update key in the wallet.
+//! This module appends later entries: it fetches the log from the DID server, finds the wallet key
+//! authorized to update the log, and signs new entries with it (`eddsa-jcs-2022`).
+//!
+//! New identities use pre-rotation: right after creation a hash of the next update key is committed
+//! in `nextKeyHashes`, and the next key is kept in the wallet. Every later entry is signed with the
+//! committed key and commits to a fresh one, so a stolen current update key cannot rewrite the DID.
+
+use std::collections::HashMap;
+
+use chacha20poly1305::aead::OsRng;
+use ed25519_dalek::{Signer, SigningKey};
+use serde_json::{Map, Value, json};
+use sha2::{Digest, Sha256};
+use tsp_sdk::{AsyncSecureStore, OwnedVid, VerifiedVid};
+
+use crate::{
+    errors::TmcpError,
+    metadata::MetadataStore,
+    pins::Pin,
+    resolver::{DidResolver, DidServerResolver, published_url},
+    settings::TmcpSettings,
+    storage, wallet,
+};
+
+/// Multicodec prefix of an ed25519 public key
+const ED25519_PUB: [u8; 2] = [0xed, 0x01];
+/// Multihash prefix of a sha2-256 digest
+const SHA2_256: [u8; 2] = [0x12, 0x20];
+
+/// Generates new signing and encryption keys for the did:webvh identity of `alias`.
+///
+/// Opens the wallet selected in `settings`, publishes the new DID document as a signed log entry
+/// on the DID server, and saves the new private VID (and the key backup). Returns the rotated DID.
+pub async fn rotate_keys(alias: &str, settings: &TmcpSettings) -> Result<String, TmcpError> {
+    settings.validate()?;
+    let client = settings.did_server_client()?;
+    let storage = storage::open(settings).await?;
+    let wallet = wallet::read_wallet(storage.as_ref()).await?;
+    let metadata = MetadataStore::from_settings(settings)?;
+    let Some(did) = wallet.resolve_alias(&wallet::wallet_alias(alias, settings))? else {
+        return Err(TmcpError::WebVh(format!("no identity for {alias} in the wallet")));
+    };
+    let resolver = DidServerResolver::new(settings, client.clone());
+    let rotation = prepare_rotation(&did, &wallet, &metadata, settings, &client, &resolver).await?;
+    storage.save(wallet.export()?).await?;
+    wallet::write_backup(&wallet, settings).await?;
+    publish_rotation(rotation, &wallet, &metadata, settings, &client).await?;
+    storage.save(wallet.export()?).await?;
+    wallet::write_backup(&wallet, settings).await?;
+    Ok(did)
+}
+
+/// Retires the identity of `alias`: deactivates its did:webvh and marks it retired next to the wallet.
+///
+/// Clients and servers refuse to start with a retired identity. Returns the retired DID.
+pub async fn retire(alias: &str, settings: &TmcpSettings) -> Result<String, TmcpError> {
+    settings.validate()?;
+    let client = settings.did_server_client()?;
+    let storage = storage::open(settings).await?;
+    let wallet = wallet::read_wallet(storage.as_ref()).await?;
+    let Some(did) = wallet.resolve_alias(&wallet::wallet_alias(alias, settings))? else {
+        return Err(TmcpError::WebVh(format!("no identity for {alias} in the wallet")));
+    };
+    deactivate(&did, &wallet, &MetadataStore::from_settings(settings)?, settings, &client).await?;
+    storage.save(wallet.export()?).await?;
+    Ok(did)
+}
+
+/// Publishes a deactivation entry for `did`, signed with the update keys in `wallet`, and marks it
+/// retired in `metadata`.
+///
+/// DIDs other than did:webvh have no log to deactivate and are only marked retired.
+pub(crate) async fn deactivate(
+    did: &str,
+    wallet: &AsyncSecureStore,
+    metadata: &MetadataStore,
+    settings: &TmcpSettings,
+    client: &reqwest::Client,
+) -> Result<(), TmcpError> {
+    if did.starts_with("did:webvh:") {
+        let mut log = fetch_log(client, settings, did).await?;
+        if active_parameters(&log).get("deactivated") != Some(&Value::Bool(true)) {
+            let state = last_state(&log);
+            if committed_hashes(&active_parameters(&log)).is_some() {
+                // Pre-rotation has to be turned off before the log can be deactivated
+                let (update_key, changes) = authorize(&log, wallet, false)?;
+                let entry = next_entry(&log, changes, state.clone(), &update_key)?;
+                publish_entry(client, settings, did, &entry).await?;
+                log.push(entry);
+            }
+            let (update_key, mut changes) = authorize(&log, wallet, false)?;
+            changes.insert("deactivated".into(), Value::Bool(true));
+            changes.insert("updateKeys".into(), json!([]));
+            let entry = next_entry(&log, changes, state, &update_key)?;
+            publish_entry(client, settings, did, &entry).await?;
+            log::info!("deactivated {} ({})", did, entry["versionId"]);
+        }
+    } else {
+        log::warn!("{} is not a did:webvh, only marking it retired", did);
+    }
+    metadata.retire(did)
+}
+
+/// Commits to the next update key of a freshly created did:webvh, whose log is `log`
+pub(crate) async fn start_pre_rotation(
+    did: &str,
+    log: Vec<Value>,
+    wallet: &AsyncSecureStore,
+    settings: &TmcpSettings,
+    client: &reqwest::Client,
+) -> Result<(), TmcpError> {
+    let (update_key, changes) = authorize(&log, wallet, true)?;
+    let entry = next_entry(&log, changes, last_state(&log), &update_key)?;
+    publish_entry(client, settings, did, &entry).await?;
+    log::info!("committed next update key of {}", did);
+    Ok(())
+}
+
+/// A key rotation that is stored in the wallet but not published yet
+pub(crate) struct Rotation {
+    did: String,
+    entry: Value,
+    private_vid: OwnedVid,
+    metadata: Option<Value>,
+}
+
+/// The wallet secret key holding the new private VID of a rotation of `did` until it is published
+fn pending_key(did: &str) -> String {
+    format!("tmcp:pending:{did}")
+}
+
+/// Generates new keys for `did` and the log entry publishing them.
+///
+/// The new private VID is kept among the wallet's secret keys and marked pending in `metadata`,
+/// and the new update key is added, so both survive if publishing fails; persist the wallet (and
+/// the key backup) before [`publish_rotation`].
+pub(crate) async fn prepare_rotation(
+    did: &str,
+    wallet: &AsyncSecureStore,
+    metadata: &MetadataStore,
+    settings: &TmcpSettings,
+    client: &reqwest::Client,
+    resolver: &dyn DidResolver,
+) -> Result<Rotation, TmcpError> {
+    if !did.starts_with("did:webvh:") {
+        return Err(TmcpError::WebVh(format!("{did} is not a did:webvh")));
+    }
+    // A previous rotation may have been published without being completed
+    finish_rotation(did, wallet, metadata, resolver).await?;
+    let log = resolver.resolve_log(did, client, settings).await?;
+    let (update_key, changes) = authorize(&log, wallet, true)?;
+
+    let (current, vid_metadata) = resolver.resolve(did).await?;
+    let private_vid = OwnedVid::bind(did, current.endpoint().clone());
+    let state = tsp_sdk::vid::did::web::vid_to_did_document(private_vid.vid());
+    let entry = next_entry(&log, changes, state, &update_key)?;
+    wallet.add_secret_key(pending_key(did), serde_json::to_vec(&private_vid)?)?;
+    metadata.set_pending_rotation(did, true)?;
+    Ok(Rotation {
+        did: did.to_string(),
+        entry,
+        private_vid,
+        metadata: vid_metadata,
+    })
+}
+
+/// Publishes a prepared rotation and only then replaces the keys of the DID in `wallet`
+pub(crate) async fn publish_rotation(
+    rotation: Rotation,
+    wallet: &AsyncSecureStore,
+    metadata: &MetadataStore,
+    settings: &TmcpSettings,
+    client: &reqwest::Client,
+) -> Result<(), TmcpError> {
+    let Rotation {
+        did,
+        entry,
+        private_vid,
+        metadata: vid_metadata,
+    } = rotation;
+    publish_entry(client, settings, &did, &entry).await?;
+    client
+        .post(settings.publish_url())
+        .json(private_vid.vid())
+        .send()
+        .await?
+        .error_for_status()?;
+    log::info!("rotated keys of {} ({})", did, entry["versionId"]);
+
+    wallet.add_private_vid(private_vid, vid_metadata)?;
+    metadata.set_pending_rotation(&did, false)
+}
+
+/// Completes a rotation of `did` marked pending in `metadata`: its keys in `wallet` replace the
+/// current ones if they were published, otherwise it is dropped.
+pub(crate) async fn finish_rotation(
+    did: &str,
+    wallet: &AsyncSecureStore,
+    metadata: &MetadataStore,
+    resolver: &dyn DidResolver,
+) -> Result<(), TmcpError> {
+    if !metadata.has_pending_rotation(did) {
+        return Ok(());
+    }
+    let (_, _, keys) = wallet.export()?;
+    let pending = keys
+        .get(&pending_key(did))
+        .and_then(|pending| serde_json::from_slice::<OwnedVid>(pending).ok());
+    match pending {
+        Some(private_vid) => {
+            let (published, vid_metadata) = resolver.resolve(did).await?;
+            if Pin::of(&published) == Pin::of(private_vid.vid()) {
+                log::info!("completing the published rotation of {}", did);
+                wallet.add_private_vid(private_vid, vid_metadata)?;
+            } else {
+                log::warn!("dropping the unpublished rotation of {}", did);
+            }
+        }
+        None => log::warn!("dropping the rotation of {}, its keys are not in the wallet", did),
+    }
+    metadata.set_pending_rotation(did, false)
+}
+
+async fn publish_entry(
+    client: &reqwest::Client,
+    settings: &TmcpSettings,
+    did: &str,
+    entry: &Value,
+) -> Result<(), TmcpError> {
+    client
+        .post(settings.publish_history_url(did))
+        .json(entry)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+/// Downloads the did:webvh log of `did` from the host named in the DID
+pub(crate) async fn fetch_log(
+    client: &reqwest::Client,
+    settings: &TmcpSettings,
+    did: &str,
+) -> Result<Vec<Value>, TmcpError> {
+    let log = client
+        .get(log_url(settings, did)?)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    parse_log(&log)
+}
+
+/// Where the log of `did:webvh:{scid}:{domain}:{path}` is published: `https://{domain}/{path}/did.jsonl`,
+/// or `https://{domain}/.well-known/did.jsonl` without a path. DIDs hosted by our DID server are
+/// fetched with `did_server_scheme`.
+#[allow(clippy::result_large_err)]
+pub(crate) fn log_url(settings: &TmcpSettings, did: &str) -> Result<String, TmcpError> {
+    did.starts_with("did:webvh:")
+        .then(|| published_url(settings, did, "did.jsonl"))
+        .flatten()
+        .map(|(url, _)| url)
+        .ok_or_else(|| TmcpError::WebVh(format!("invalid did:webvh {did}")))
+}
+
+/// Parses a did:webvh log, one JSON entry per line
+#[allow(clippy::result_large_err)]
+pub(crate) fn parse_log(log: &str) -> Result<Vec<Value>, TmcpError> {
+    let entries = log
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(serde_json::from_str)
+        .collect::<Result<Vec<Value>, _>>()?;
+    if entries.is_empty() {
+        return Err(TmcpError::WebVh("empty did:webvh log".into()));
+    }
+    Ok(entries)
+}
+
+/// The parameters in effect after the last entry; each entry only lists the ones it changes
+pub(crate) fn active_parameters(log: &[Value]) -> Map<String, Value> {
+    let mut parameters = Map::new();
+    for entry in log {
+        if let Some(Value::Object(changes)) = entry.get("parameters") {
+            parameters.extend(changes.clone());
+        }
+    }
+    parameters
+}
+
+/// The DID document of the last entry
+pub(crate) fn last_state(log: &[Value]) -> Value {
+    log.last().map(|entry| entry["state"].clone()).unwrap_or_default()
+}
+
+/// The committed `nextKeyHashes`, if pre-rotation is active
+fn committed_hashes(parameters: &Map<String, Value>) -> Option<Vec<&str>> {
+    let hashes: Vec<&str> = parameters
+        .get("nextKeyHashes")?
+        .as_array()?
+        .iter()
+        .filter_map(Value::as_str)
+        .collect();
+    (!hashes.is_empty()).then_some(hashes)
+}
+
+/// Picks the key that may sign the entry following `log`, and the parameter changes it must carry.
+///
+/// With pre-rotation active only a wallet key matching a committed hash may sign, and it becomes
+/// the new update key; there is no fallback to the current update key. Otherwise a key from the
+/// active `updateKeys` signs. With `commit` a fresh next key is stored in the wallet and committed,
+/// without it pre-rotation is turned off.
+#[allow(clippy::result_large_err)]
+pub(crate) fn authorize(
+    log: &[Value],
+    wallet: &AsyncSecureStore,
+    commit: bool,
+) -> Result<(SigningKey, Map<String, Value>), TmcpError> {
+    let parameters = active_parameters(log);
+    let (_, _, keys) = wallet.export()?;
+    let mut changes = Map::new();
+    let update_key = match committed_hashes(&parameters) {
+        Some(hashes) => {
+            let key = wallet_keys(&keys)
+                .find(|key| hashes.contains(&key_hash(key).as_str()))
+                .ok_or_else(|| TmcpError::WebVh("none of the committed next keys is in the wallet".into()))?;
+            changes.insert("updateKeys".into(), json!([multikey(&key)]));
+            key
+        }
+        None => {
+            let update_keys = parameters
+                .get("updateKeys")
+                .and_then(Value::as_array)
+                .ok_or_else(|| TmcpError::WebVh("log has no updateKeys".into()))?;
+            wallet_keys(&keys)
+                .find(|key| update_keys.iter().any(|k| k.as_str() == Some(&multikey(key))))
+                .ok_or_else(|| TmcpError::WebVh("none of the updateKeys is in the wallet".into()))?
+        }
+    };
+    let next_key_hashes = if commit {
+        vec![key_hash(&new_update_key(wallet)?)]
+    } else {
+        Vec::new()
+    };
+    changes.insert("nextKeyHashes".into(), json!(next_key_hashes));
+    Ok((update_key, changes))
+}
+
+/// The ed25519 keys among the wallet's secret keys
+fn wallet_keys(keys: &HashMap<String, Vec<u8>>) -> impl Iterator<Item = SigningKey> + '_ {
+    keys.values().filter_map(|secret| signing_key(secret))
+}
+
+/// Generates an update key and keeps it in `wallet`
+#[allow(clippy::result_large_err)]
+fn new_update_key(wallet: &AsyncSecureStore) -> Result<SigningKey, TmcpError> {
+    let key = SigningKey::generate(&mut OsRng);
+    let multikey = multikey(&key);
+    wallet.add_secret_key(format!("did:key:{multikey}#{multikey}"), key.to_bytes().to_vec())?;
+    Ok(key)
+}
+
+/// The hash committing to `key` in `nextKeyHashes`
+pub(crate) fn key_hash(key: &SigningKey) -> String {
+    multihash(multikey(key).as_bytes())
+}
+
+/// Reads an ed25519 secret key, stored either as seed or as seed followed by the public key
+pub(crate) fn signing_key(secret: &[u8]) -> Option<SigningKey> {
+    let seed: [u8; 32] = secret.get(..32)?.try_into().ok()?;
+    Some(SigningKey::from_bytes(&seed))
+}
+
+/// The `z`-prefixed base58btc multikey of the public half of `key`
+pub(crate) fn multikey(key: &SigningKey) -> String {
+    let mut bytes = ED25519_PUB.to_vec();
+    bytes.extend_from_slice(key.verifying_key().as_bytes());
+    format!("z{}", bs58::encode(bytes).into_string())
+}
+
+/// Builds the signed entry following `log`, with the given parameter changes and DID document
+#[allow(clippy::result_large_err)]
+pub(crate) fn next_entry(
+    log: &[Value],
+    parameters: Map<String, Value>,
+    state: Value,
+    update_key: &SigningKey,
+) -> Result<Value, TmcpError> {
+    let previous = log
+        .last()
+        .and_then(|entry| entry["versionId"].as_str())
+        .ok_or_else(|| TmcpError::WebVh("log entry without versionId".into()))?;
+    let number: u64 = previous
+        .split_once('-')
+        .and_then(|(number, _)| number.parse().ok())
+        .ok_or_else(|| TmcpError::WebVh(format!("invalid versionId {previous}")))?;
+
+    // The entry hash is computed with the previous versionId in place of the new one
+    let mut entry = json!({
+        "versionId": previous,
+        "versionTime": now(),
+        "parameters": parameters,
+        "state": state,
+    });
+    let entry_hash = multihash(&jcs(&entry)?);
+    entry["versionId"] = Value::String(format!("{}-{}", number + 1, entry_hash));
+    sign(entry, update_key)
+}
+
+/// Checks that `log` belongs to `did` and its chain: the SCID, version numbers, entry hashes, and that
+/// every entry is signed by a key authorized by the entries before it (honouring pre-rotation
+/// commitments).
+pub(crate) fn verify_log(did: &str, log: &[Value]) -> Result<(), String> {
+    let scid = did
+        .strip_prefix("did:webvh:")
+        .and_then(|rest| rest.split(':').next())
+        .ok_or_else(|| format!("{did} is not a did:webvh"))?;
+    let first = log.first().ok_or("empty log")?;
+    if first["parameters"]["scid"].as_str() != Some(scid) {
+        return Err(format!("the log is not the one of SCID {scid}"));
+    }
+    if derive_scid(first, scid).map_err(|e| e.to_string())? != scid {
+        return Err(format!("the first entry does not match SCID {scid}"));
+    }
+
+    let mut parameters = Map::new();
+    for (index, entry) in log.iter().enumerate() {
+        let version_id = entry["versionId"].as_str().ok_or("entry without versionId")?;
+        if entry["state"]["id"].as_str() != Some(did) {
+            return Err(format!("{version_id} is the state of another DID"));
+        }
+        let (number, _) = version_id
+            .split_once('-')
+            .ok_or_else(|| format!("invalid versionId {version_id}"))?;
+        if number != (index + 1).to_string() {
+            return Err(format!("entry {} has versionId {version_id}", index + 1));
+        }
+        let mut unsigned = entry.clone();
+        let proofs = unsigned
+            .as_object_mut()
+            .and_then(|entry| entry.remove("proof"))
+            .ok_or_else(|| format!("{version_id} has no proof"))?;
+
+        // The entry hash is computed with the previous versionId, the SCID for the first entry
+        unsigned["versionId"] = match index {
+            0 => Value::String(scid.to_string()),
+            _ => log[index - 1]["versionId"].clone(),
+        };
+        let entry_hash = multihash(&jcs(&unsigned).map_err(|e| e.to_string())?);
+        if version_id != format!("{number}-{entry_hash}") {
+            return Err(format!("{version_id} does not match its entry hash"));
+        }
+        unsigned["versionId"] = Value::String(version_id.to_string());
+
+        let changes = entry["parameters"].as_object().cloned().unwrap_or_default();
+        let authorized: Vec<String> = if index == 0 {
+            string_list(changes.get("updateKeys"))
+        } else {
+            match committed_hashes(&parameters) {
+                Some(hashes) => {
+                    let update_keys = string_list(changes.get("updateKeys"));
+                    if update_keys.is_empty()
+                        || !update_keys
+                            .iter()
+                            .all(|key| hashes.contains(&multihash(key.as_bytes()).as_str()))
+                    {
+                        return Err(format!("{version_id} breaks the pre-rotation commitment"));
+                    }
+                    update_keys
+                }
+                None => string_list(parameters.get("updateKeys")),
+            }
+        };
+
+        let proofs = match proofs {
+            Value::Array(proofs) => proofs,
+            proof => vec![proof],
+        };
+        if !proofs.iter().any(|proof| verify_proof(&unsigned, proof, &authorized)) {
+            return Err(format!("{version_id} is not signed by an authorized update key"));
+        }
+        parameters.extend(changes);
+    }
+    Ok(())
+}
+
+/// The SCID of the log starting with `first`: the hash of the entry without its proof, with `{SCID}`
+/// in place of the versionId and of every occurrence of the SCID
+#[allow(clippy::result_large_err)]
+pub(crate) fn derive_scid(first: &Value, scid: &str) -> Result<String, TmcpError> {
+    let mut preliminary = first.clone();
+    if let Some(entry) = preliminary.as_object_mut() {
+        entry.remove("proof");
+        entry.insert("versionId".into(), Value::String("{SCID}".into()));
+    }
+    let preliminary = serde_json::to_string(&preliminary)?.replace(scid, "{SCID}");
+    Ok(multihash(&jcs(&serde_json::from_str(&preliminary)?)?))
+}
+
+fn string_list(value: Option<&Value>) -> Vec<String> {
+    value
+        .and_then(Value::as_array)
+        .map(|list| list.iter().filter_map(Value::as_str).map(str::to_string).collect())
+        .unwrap_or_default()
+}
+
+/// Checks an `eddsa-jcs-2022` proof over `entry` made with one of the `authorized` multikeys
+fn verify_proof(entry: &Value, proof: &Value, authorized: &[String]) -> bool {
+    let mut proof = proof.clone();
+    let Some(proof_value) = proof.as_object_mut().and_then(|proof| proof.remove("proofValue")) else {
+        return false;
+    };
+    let Some(multikey) = proof["verificationMethod"]
+        .as_str()
+        .and_then(|method| method.split_once('#'))
+        .map(|(_, fragment)| fragment)
+        .filter(|multikey| authorized.iter().any(|key| key == multikey))
+    else {
+        return false;
+    };
+    let public_key = multikey
+        .strip_prefix('z')
+        .and_then(|key| bs58::decode(key).into_vec().ok())
+        .and_then(|key| key.strip_prefix(&ED25519_PUB).map(<[u8]>::to_vec))
+        .and_then(|key| <[u8; 32]>::try_from(key).ok())
+        .and_then(|key| ed25519_dalek::VerifyingKey::from_bytes(&key).ok());
+    let signature = proof_value
+        .as_str()
+        .and_then(|value| value.strip_prefix('z'))
+        .and_then(|value| bs58::decode(value).into_vec().ok())
+        .and_then(|value| ed25519_dalek::Signature::from_slice(&value).ok());
+    let (Some(public_key), Some(signature), Ok(proof_config), Ok(entry)) =
+        (public_key, signature, jcs(&proof), jcs(entry))
+    else {
+        return false;
+    };
+    let mut hash_data = Sha256::digest(proof_config).to_vec();
+    hash_data.extend_from_slice(&Sha256::digest(entry));
+    public_key.verify_strict(&hash_data, &signature).is_ok()
+}
+
+/// Adds an `eddsa-jcs-2022` Data Integrity proof to `entry`
+#[allow(clippy::result_large_err)]
+pub(crate) fn sign(mut entry: Value, key: &SigningKey) -> Result<Value, TmcpError> {
+    let multikey = multikey(key);
+    let mut proof = json!({
+        "type": "DataIntegrityProof",
+        "cryptosuite": "eddsa-jcs-2022",
+        "verificationMethod": format!("did:key:{multikey}#{multikey}"),
+        "created": now(),
+        "proofPurpose": "assertionMethod",
+    });
+    let mut hash_data = Sha256::digest(jcs(&proof)?).to_vec();
+    hash_data.extend_from_slice(&Sha256::digest(jcs(&entry)?));
+    let signature = key.sign(&hash_data);
+    proof["proofValue"] = Value::String(format!(
+        "z{}",
+        bs58::encode(signature.to_bytes()).into_string()
+    ));
+    entry["proof"] = json!([proof]);
+    Ok(entry)
+}
+
+/// JSON Canonicalization Scheme (RFC 8785)
+#[allow(clippy::result_large_err)]
+fn jcs(value: &Value) -> Result<Vec<u8>, TmcpError> {
+    Ok(serde_jcs::to_vec(value)?)
+}
+
+/// base58btc encoded sha2-256 multihash
+pub(crate) fn multihash(data: &[u8]) -> String {
+    let mut bytes = SHA2_256.to_vec();
+    bytes.extend_from_slice(&Sha256::digest(data));
+    bs58::encode(bytes).into_string()
+}
+
+fn now() -> String {
+    chrono::Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string()
+}